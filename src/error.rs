@@ -8,6 +8,8 @@ use warp::{
 #[derive(Debug)]
 pub enum Error {
     Unauthorized,
+    /// A form was posted without the CSRF token of the session.
+    Forbidden,
     Database(sqlx::Error),
     Template(askama::Error),
 }
@@ -43,6 +45,21 @@ pub async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Infall
             warp::reply::html(NotFoundTemplate.render().unwrap()),
             StatusCode::NOT_FOUND,
         ))
+    } else if let Some(Error::Forbidden) = rejection.find() {
+        let code = StatusCode::FORBIDDEN;
+        Ok(warp::reply::with_status(
+            warp::reply::html(
+                ErrorTemplate {
+                    code,
+                    details: String::from(
+                        "Das Formular ist abgelaufen oder ungültig. Bitte lade die Seite neu und versuche es nochmals.",
+                    ),
+                }
+                .render()
+                .unwrap(),
+            ),
+            code,
+        ))
     } else {
         let code = StatusCode::INTERNAL_SERVER_ERROR;
         Ok(warp::reply::with_status(
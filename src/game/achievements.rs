@@ -0,0 +1,144 @@
+use super::message::ServerMessage;
+use crate::{
+    model::{
+        achievement::{self, Achievement},
+        user::UserId,
+    },
+    Error,
+};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use sqlx::{Postgres, Transaction};
+use std::collections::{HashMap, HashSet};
+
+/// How many players count as top ten.
+const TOP_SIZE: usize = 10;
+/// Days in a row a player has to play for the streak achievement.
+const STREAK_DAYS: i32 = 7;
+
+/// Decides which achievements players unlock. Unlocks are kept in memory
+/// so that checking them after every state change doesn't touch the database,
+/// new ones are written back in snapshots.
+pub struct Achievements {
+    unlocked: HashMap<UserId, HashSet<Achievement>>,
+    /// Unlocks that haven't been written yet.
+    pending: Vec<(UserId, Achievement)>,
+    /// Since when each player in the top ten has been there without interruption.
+    top_since: HashMap<UserId, DateTime<Utc>>,
+    /// Whether the top ten changed since it was last written.
+    top_changed: bool,
+    /// The last day each player was recorded as playing.
+    played: HashMap<UserId, NaiveDate>,
+}
+
+impl Achievements {
+    pub async fn load() -> Result<Self, Error> {
+        let mut unlocked: HashMap<UserId, HashSet<Achievement>> = HashMap::new();
+        for (user_id, achievement) in achievement::all().await? {
+            unlocked.entry(user_id).or_default().insert(achievement);
+        }
+
+        Ok(Self {
+            unlocked,
+            pending: Vec::new(),
+            top_since: achievement::top_since().await?.into_iter().collect(),
+            top_changed: false,
+            played: HashMap::new(),
+        })
+    }
+
+    /// Unlocks the achievement if it is new and returns the message announcing it.
+    pub fn unlock(&mut self, user_id: UserId, achievement: Achievement) -> Option<ServerMessage> {
+        if !self
+            .unlocked
+            .entry(user_id)
+            .or_default()
+            .insert(achievement)
+        {
+            return None;
+        }
+        self.pending.push((user_id, achievement));
+
+        Some(ServerMessage::AchievementUnlocked {
+            achievement,
+            title: achievement.title().to_owned(),
+        })
+    }
+
+    /// Checks the achievements that depend on a player's score.
+    pub fn check_score(&mut self, user_id: UserId, score: i32) -> Vec<ServerMessage> {
+        let mut messages = Vec::new();
+
+        if score >= 1000 {
+            messages.extend(self.unlock(user_id, Achievement::Points1000));
+        }
+
+        messages
+    }
+
+    /// Checks who has been in the top ten for a whole day, given the current top ten.
+    pub fn check_top(&mut self, top: &[UserId]) -> Vec<(UserId, ServerMessage)> {
+        let now = Utc::now();
+        let top = &top[..top.len().min(TOP_SIZE)];
+
+        let before = self.top_since.len();
+        self.top_since.retain(|user_id, _| top.contains(user_id));
+        self.top_changed |= self.top_since.len() != before;
+        for user_id in top {
+            if !self.top_since.contains_key(user_id) {
+                self.top_since.insert(*user_id, now);
+                self.top_changed = true;
+            }
+        }
+
+        let mut messages = Vec::new();
+        for user_id in top {
+            if now - self.top_since[user_id] >= Duration::days(1) {
+                if let Some(message) = self.unlock(*user_id, Achievement::TopTenForADay) {
+                    messages.push((*user_id, message));
+                }
+            }
+        }
+
+        messages
+    }
+
+    /// Records that the player played today, at most once per day.
+    pub async fn check_played(&mut self, user_id: UserId) -> Result<Option<ServerMessage>, Error> {
+        let today = Utc::now().naive_utc().date();
+        if self.played.get(&user_id) == Some(&today) {
+            return Ok(None);
+        }
+
+        let days = achievement::record_play(user_id, today, STREAK_DAYS).await?;
+        self.played.insert(user_id, today);
+
+        if days >= STREAK_DAYS as i64 {
+            Ok(self.unlock(user_id, Achievement::SevenDayStreak))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Writes all new unlocks and the top ten, if it changed, as part of a snapshot.
+    pub async fn write(&self, transaction: &mut Transaction<'_, Postgres>) -> Result<(), Error> {
+        if !self.pending.is_empty() {
+            achievement::unlock_all(transaction, &self.pending).await?;
+        }
+        if self.top_changed {
+            let top_since: Vec<_> = self
+                .top_since
+                .iter()
+                .map(|(user_id, since)| (*user_id, *since))
+                .collect();
+            achievement::set_top_since(transaction, &top_since).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Forgets the changes once a snapshot containing them has been committed.
+    pub fn clean(&mut self) {
+        self.pending.clear();
+        self.top_changed = false;
+    }
+}
@@ -0,0 +1,96 @@
+use super::message::{Channel, RoomId, ServerMessage};
+use crate::{database::get_pool, env::CHAT_BLOCKLIST, model::user::UserId, Error};
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+/// Maximum length of a chat message in characters.
+pub const MAX_LENGTH: usize = 500;
+/// How many past messages per channel a client receives on init.
+const HISTORY_SIZE: i64 = 50;
+/// Sustained messages per second a user may send, every message is stored and broadcast.
+pub const RATE: f64 = 0.5;
+/// Messages a user may send at once after being quiet.
+pub const BURST: f64 = 5.0;
+
+/// Matches any of the comma separated words in `CHAT_BLOCKLIST`, ignoring case.
+static BLOCKLIST: Lazy<Option<Regex>> = Lazy::new(|| {
+    let words: Vec<String> = CHAT_BLOCKLIST
+        .split(',')
+        .map(str::trim)
+        .filter(|word| !word.is_empty())
+        .map(regex::escape)
+        .collect();
+
+    if words.is_empty() {
+        None
+    } else {
+        Some(Regex::new(&format!(r"(?i)\b({})\b", words.join("|"))).unwrap())
+    }
+});
+
+/// Replaces every blocked word with asterisks.
+pub fn filter(text: &str) -> String {
+    match &*BLOCKLIST {
+        Some(blocklist) => blocklist
+            .replace_all(text, |captures: &regex::Captures| {
+                "*".repeat(captures[0].chars().count())
+            })
+            .into_owned(),
+        None => text.to_owned(),
+    }
+}
+
+/// Stores a message, `room_id` is `None` for the global channel.
+/// The history of a room is deleted along with the room once the last member leaves,
+/// a room of the same name created later starts out empty.
+pub async fn save(
+    user_id: UserId,
+    room_id: Option<RoomId>,
+    text: &str,
+) -> Result<DateTime<Utc>, Error> {
+    Ok(sqlx::query!(
+        "INSERT INTO chat_messages (user_id, room_id, text)
+        VALUES ($1, $2, $3)
+        RETURNING sent_at",
+        user_id,
+        room_id,
+        text,
+    )
+    .fetch_one(get_pool())
+    .await?
+    .sent_at)
+}
+
+/// The latest messages of a channel, oldest first.
+pub async fn history(room_id: Option<RoomId>) -> Result<Vec<ServerMessage>, Error> {
+    let channel = if room_id.is_some() {
+        Channel::Room
+    } else {
+        Channel::Global
+    };
+
+    let mut messages: Vec<ServerMessage> = sqlx::query!(
+        "SELECT username, text, sent_at
+        FROM chat_messages
+        NATURAL JOIN users
+        WHERE room_id IS NOT DISTINCT FROM $1
+        ORDER BY message_id DESC
+        LIMIT $2",
+        room_id,
+        HISTORY_SIZE,
+    )
+    .fetch_all(get_pool())
+    .await?
+    .into_iter()
+    .map(|row| ServerMessage::Chat {
+        username: row.username,
+        text: row.text,
+        sent_at: row.sent_at,
+        channel,
+    })
+    .collect();
+    messages.reverse();
+
+    Ok(messages)
+}
@@ -0,0 +1,249 @@
+use super::limit::{RateLimiter, Verdict};
+use super::message::{Client, Outgoing, ServerMessage};
+use super::protocol::{ErrorCode, Tagged};
+use super::rules::{Change, GameRules, Outcome, View};
+use super::shop::{InventoryEntry, Item, Shop};
+use super::SCOREBOARD_SIZE;
+use crate::{
+    model::{achievement::Achievement, user::UserId},
+    Error,
+};
+use futures::future::{BoxFuture, FutureExt};
+use log::*;
+use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, Transaction};
+use std::time::Duration;
+
+const ROUND_DURATION: Duration = Duration::from_secs(60 * 60);
+const AUTO_CLICK_INTERVAL: Duration = Duration::from_secs(5);
+/// Sustained increments per second a player may send.
+const CLICK_RATE: f64 = 10.0;
+/// Increments a player may send at once after being idle.
+const CLICK_BURST: f64 = 20.0;
+/// Points every auto-clicker generates per interval.
+const AUTO_CLICK_POINTS: i32 = 5;
+
+/// Every click is worth a point plus one per multiplier,
+/// auto-clickers generate points on their own. Points can be spent on upgrades,
+/// the scoreboard is announced at the end of each round.
+pub struct Clicker {
+    limiter: RateLimiter,
+    shop: Shop,
+}
+
+impl Clicker {
+    pub fn new() -> Self {
+        Self {
+            limiter: RateLimiter::new(CLICK_RATE, CLICK_BURST),
+            shop: Shop::new(),
+        }
+    }
+}
+
+impl Default for Clicker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", content = "data")]
+pub enum ClickerMessage {
+    Increment,
+    Buy { item: Item },
+}
+
+impl Tagged for ClickerMessage {
+    type Tag = ClickerMessageType;
+}
+
+/// The `type` tags of [`ClickerMessage`].
+#[derive(Deserialize)]
+pub enum ClickerMessageType {
+    Increment,
+    Buy,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum ClickerUpdate {
+    Inventory(Vec<InventoryEntry>),
+    /// What a player earned while they were away.
+    OfflineEarnings {
+        seconds: i64,
+        points: i32,
+    },
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ClickerPayload {
+    /// Announces the final scoreboard of the current round.
+    RoundEnd,
+    /// Pays out the points the auto-clickers of a player generated since the last payout.
+    AutoClick(UserId),
+}
+
+fn auto_click(user_id: UserId) -> Change<ClickerPayload> {
+    Change::Schedule {
+        after: AUTO_CLICK_INTERVAL,
+        payload: ClickerPayload::AutoClick(user_id),
+    }
+}
+
+impl GameRules for Clicker {
+    type Message = ClickerMessage;
+    type Update = ClickerUpdate;
+    type Payload = ClickerPayload;
+
+    fn load(&mut self) -> BoxFuture<'_, Result<(), Error>> {
+        async move {
+            self.shop = Shop::load().await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    /// Inventories are written in the same snapshot as the scores their prices were taken from.
+    fn write<'a>(
+        &'a self,
+        transaction: &'a mut Transaction<'_, Postgres>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.shop.write(transaction).boxed()
+    }
+
+    fn clean(&mut self) {
+        self.shop.clean();
+    }
+
+    fn start(&mut self, view: &View<ClickerPayload>) -> Outcome<ClickerPayload, ClickerUpdate> {
+        let mut outcome = Outcome::new();
+        if !view.is_scheduled(&ClickerPayload::RoundEnd) {
+            outcome = outcome.change(Change::Schedule {
+                after: ROUND_DURATION,
+                payload: ClickerPayload::RoundEnd,
+            });
+        }
+        for (user_id, _) in self.shop.owners(Item::AutoClicker) {
+            if !view.is_scheduled(&ClickerPayload::AutoClick(user_id)) {
+                outcome = outcome.change(auto_click(user_id));
+            }
+        }
+        outcome
+    }
+
+    fn handle_message(
+        &mut self,
+        view: &View<ClickerPayload>,
+        user_id: UserId,
+        message: ClickerMessage,
+    ) -> Outcome<ClickerPayload, ClickerUpdate> {
+        match message {
+            ClickerMessage::Increment => match self.limiter.check(user_id) {
+                Verdict::Allowed => Outcome::new()
+                    .change(Change::AddScore {
+                        user_id,
+                        delta: 1 + self.shop.owned(user_id, Item::Multiplier),
+                    })
+                    .change(Change::Unlock {
+                        user_id,
+                        achievement: Achievement::FirstClick,
+                    }),
+                Verdict::Limited => Outcome::new().reply(ServerMessage::RateLimited),
+                Verdict::Flagged(rejected) => {
+                    warn!(
+                        "flagging user {} for {} rejected increments",
+                        user_id, rejected
+                    );
+                    Outcome::new()
+                        .change(Change::Flag { user_id, rejected })
+                        .reply(ServerMessage::RateLimited)
+                }
+            },
+            ClickerMessage::Buy { item } => {
+                let price = item.price(self.shop.owned(user_id, item));
+                match view.scoreboard.score(user_id) {
+                    Some(score) if score >= price => {
+                        self.shop.add(user_id, item);
+                        let mut outcome = Outcome::new();
+                        if item == Item::AutoClicker
+                            && !view.is_scheduled(&ClickerPayload::AutoClick(user_id))
+                        {
+                            outcome = outcome.change(auto_click(user_id));
+                        }
+                        outcome
+                            .change(Change::AddScore {
+                                user_id,
+                                delta: -price,
+                            })
+                            .send(
+                                Client::User(user_id),
+                                Outgoing::Game(ClickerUpdate::Inventory(
+                                    self.shop.inventory(user_id),
+                                )),
+                            )
+                    }
+                    _ => Outcome::new().error(
+                        ErrorCode::InsufficientFunds,
+                        format!("{:?} costs {} points", item, price),
+                    ),
+                }
+            }
+        }
+    }
+
+    fn handle_event(
+        &mut self,
+        view: &View<ClickerPayload>,
+        payload: &ClickerPayload,
+    ) -> Outcome<ClickerPayload, ClickerUpdate> {
+        match payload {
+            ClickerPayload::RoundEnd => {
+                info!("round ended");
+
+                Outcome::new()
+                    .send(
+                        Client::All,
+                        ServerMessage::UpdateScoreboard(view.scoreboard.top(SCOREBOARD_SIZE)),
+                    )
+                    .change(Change::Schedule {
+                        after: ROUND_DURATION,
+                        payload: ClickerPayload::RoundEnd,
+                    })
+            }
+            // Players that are away get paid through their passive income once they are back.
+            ClickerPayload::AutoClick(user_id) => {
+                let count = self.shop.owned(*user_id, Item::AutoClicker);
+                if count == 0 {
+                    return Outcome::new();
+                }
+
+                let mut outcome = Outcome::new().change(auto_click(*user_id));
+                if view.scoreboard.is_online(*user_id) {
+                    outcome = outcome.change(Change::AddScore {
+                        user_id: *user_id,
+                        delta: count.saturating_mul(AUTO_CLICK_POINTS),
+                    });
+                }
+                outcome
+            }
+        }
+    }
+
+    fn passive_income(&self, _view: &View<ClickerPayload>, user_id: UserId) -> f64 {
+        (self.shop.owned(user_id, Item::AutoClicker) * AUTO_CLICK_POINTS) as f64
+            / AUTO_CLICK_INTERVAL.as_secs_f64()
+    }
+
+    fn offline_earnings(&self, away: chrono::Duration, points: i32) -> Option<ClickerUpdate> {
+        Some(ClickerUpdate::OfflineEarnings {
+            seconds: away.num_seconds(),
+            points,
+        })
+    }
+
+    fn init(&self, _view: &View<ClickerPayload>, user_id: UserId) -> Vec<Outgoing<ClickerUpdate>> {
+        vec![Outgoing::Game(ClickerUpdate::Inventory(
+            self.shop.inventory(user_id),
+        ))]
+    }
+}
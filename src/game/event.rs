@@ -1,70 +1,362 @@
-
-use std::collections::VecDeque;
+use crate::{database::get_pool, model::season::SeasonId, Error};
 use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
 use std::time::{Duration, Instant};
 
-#[derive(Ord, PartialOrd, Eq, PartialEq)]
-pub struct Event {
+/// Either an event the game loop handles itself or one of the game rules.
+/// Untagged, so both kinds are stored the same way the rules' payloads serialize.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Payload<P> {
+    Common(CommonPayload),
+    Game(P),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum CommonPayload {
+    /// Archives the standings of a season and starts the next one.
+    SeasonEnd(SeasonId),
+}
+
+/// Refers to a scheduled event so it can be cancelled or rescheduled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EventHandle(i32);
+
+#[derive(Debug)]
+struct Event<T> {
     expires: DateTime<Utc>,
-    id: u64,
+    event_id: i32,
+    payload: T,
 }
 
-impl Event {
-    fn expires_at(&self) -> Instant {
-        let duration = self.expires
-            .signed_duration_since(Utc::now())
-            .to_std()
-            .unwrap();
-        Instant::now() + duration
-    }
+/// Events that expired while the server was down are due immediately.
+fn expires_at(expires: DateTime<Utc>) -> Instant {
+    let duration = expires
+        .signed_duration_since(Utc::now())
+        .to_std()
+        .unwrap_or(Duration::from_secs(0));
+    Instant::now() + duration
 }
 
-pub struct EventQueue {
-    deque: VecDeque<Event>,
-    next: Instant,
+/// A min-heap of events ordered by expiry.
+///
+/// Cancelling or rescheduling an event leaves its old heap entry behind,
+/// stale entries are skipped once they reach the top of the heap.
+pub struct EventQueue<T> {
+    heap: BinaryHeap<Reverse<(DateTime<Utc>, i32)>>,
+    events: HashMap<i32, Event<T>>,
 }
 
-impl EventQueue {
-    pub fn new() -> Self {
+impl<T> EventQueue<T> {
+    pub fn payloads(&self) -> impl Iterator<Item = &T> {
+        self.events.values().map(|event| &event.payload)
+    }
+
+    /// The pending events with the handles to cancel or reschedule them.
+    pub fn scheduled(&self) -> impl Iterator<Item = (EventHandle, &T)> {
+        self.events
+            .values()
+            .map(|event| (EventHandle(event.event_id), &event.payload))
+    }
+}
+
+impl<T: Clone + Serialize + DeserializeOwned + PartialEq> EventQueue<T> {
+    fn new() -> Self {
         Self {
-            deque: VecDeque::new(),
-            next: Instant::now() + Duration::from_secs(60 * 60 * 24 * 1000),
+            heap: BinaryHeap::new(),
+            events: HashMap::new(),
         }
     }
 
-    fn update(&mut self, event: &Event) {
-        let instant = event.expires_at();
+    /// Restores all pending events from the database.
+    pub async fn load() -> Result<Self, Error> {
+        let mut queue = Self::new();
+
+        for row in sqlx::query!(
+            "SELECT event_id, expires, payload
+            FROM events",
+        )
+        .fetch_all(get_pool())
+        .await?
+        {
+            match serde_json::from_value(row.payload) {
+                Ok(payload) => queue.push(Event {
+                    expires: row.expires,
+                    event_id: row.event_id,
+                    payload,
+                }),
+                Err(err) => log::warn!("dropping event {}: {}", row.event_id, err),
+            }
+        }
+
+        log::info!("loaded {} pending events", queue.events.len());
+
+        Ok(queue)
+    }
+
+    fn push(&mut self, event: Event<T>) {
+        self.heap.push(Reverse((event.expires, event.event_id)));
+        self.events.insert(event.event_id, event);
 
-        if instant < self.next {
-            self.next = instant;
+        if self.heap.len() > 2 * self.events.len() + 16 {
+            self.heap = self
+                .events
+                .values()
+                .map(|event| Reverse((event.expires, event.event_id)))
+                .collect();
         }
     }
 
-    pub async fn next(&mut self) -> Event {
-        tokio::time::sleep_until(
-            tokio::time::Instant::from_std(self.next)).await;
-        
-        let next = self.deque.pop_front().unwrap();
-        
-        if let Some(event) = self.deque.front() {
-            let instant = event.expires_at();
-            if instant < self.next {
-                self.next = instant;
+    /// Returns the earliest entry of the heap that still refers to a scheduled event.
+    fn peek(&mut self) -> Option<(DateTime<Utc>, i32)> {
+        while let Some(&Reverse((expires, event_id))) = self.heap.peek() {
+            match self.events.get(&event_id) {
+                Some(event) if event.expires == expires => return Some((expires, event_id)),
+                _ => {
+                    self.heap.pop();
+                }
             }
+        }
+
+        None
+    }
+
+    /// Takes the earliest event off the heap. It stays scheduled until it is completed,
+    /// cancelled or rescheduled, so it is only returned once.
+    fn pop(&mut self) -> Option<(EventHandle, T)> {
+        let (_, event_id) = self.peek()?;
+        self.heap.pop();
+        let payload = self.events[&event_id].payload.clone();
+        Some((EventHandle(event_id), payload))
+    }
+
+    /// Waits until the earliest event expires and returns it.
+    /// If the queue is empty, this never resolves.
+    pub async fn next(&mut self) -> (EventHandle, T) {
+        if let Some((expires, _)) = self.peek() {
+            tokio::time::sleep_until(tokio::time::Instant::from_std(expires_at(expires))).await;
+            self.pop().unwrap()
         } else {
-            self.next = Instant::now() + Duration::from_secs(60 * 60 * 24 * 1000);
+            futures::future::pending().await
         }
+    }
+
+    pub fn is_scheduled(&self, payload: &T) -> bool {
+        self.payloads().any(|scheduled| scheduled == payload)
+    }
+
+    pub async fn insert(
+        &mut self,
+        expires: DateTime<Utc>,
+        payload: T,
+    ) -> Result<EventHandle, Error> {
+        let event_id = sqlx::query!(
+            "INSERT INTO events (expires, payload)
+            VALUES ($1, $2)
+            RETURNING event_id",
+            expires,
+            serde_json::to_value(&payload).unwrap(),
+        )
+        .fetch_one(get_pool())
+        .await?
+        .event_id;
+
+        self.push(Event {
+            expires,
+            event_id,
+            payload,
+        });
+
+        Ok(EventHandle(event_id))
+    }
+
+    pub async fn insert_after(
+        &mut self,
+        duration: Duration,
+        payload: T,
+    ) -> Result<EventHandle, Error> {
+        self.insert(
+            Utc::now() + chrono::Duration::from_std(duration).unwrap(),
+            payload,
+        )
+        .await
+    }
+
+    /// Removes a handled event and stores the events that follow it in the same transaction,
+    /// so that stopping in between can't lose them.
+    pub async fn complete(
+        &mut self,
+        handle: EventHandle,
+        next: Vec<(DateTime<Utc>, T)>,
+    ) -> Result<(), Error> {
+        let mut transaction = get_pool().begin().await?;
+
+        sqlx::query!(
+            "DELETE FROM events
+            WHERE event_id = $1",
+            handle.0,
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        let mut inserted = Vec::with_capacity(next.len());
+        for (expires, payload) in next {
+            let event_id = sqlx::query!(
+                "INSERT INTO events (expires, payload)
+                VALUES ($1, $2)
+                RETURNING event_id",
+                expires,
+                serde_json::to_value(&payload).unwrap(),
+            )
+            .fetch_one(&mut transaction)
+            .await?
+            .event_id;
+
+            inserted.push(Event {
+                expires,
+                event_id,
+                payload,
+            });
+        }
+
+        transaction.commit().await?;
+
+        self.events.remove(&handle.0);
+        for event in inserted {
+            self.push(event);
+        }
+
+        Ok(())
+    }
+
+    /// Removes a pending event, returning its payload if it was still scheduled.
+    pub async fn cancel(&mut self, handle: EventHandle) -> Result<Option<T>, Error> {
+        if !self.events.contains_key(&handle.0) {
+            return Ok(None);
+        }
+
+        sqlx::query!(
+            "DELETE FROM events
+            WHERE event_id = $1",
+            handle.0,
+        )
+        .execute(get_pool())
+        .await?;
+
+        Ok(self.events.remove(&handle.0).map(|event| event.payload))
+    }
+
+    /// Moves a pending event to a new expiry, returns `false` if it was no longer scheduled.
+    pub async fn reschedule(
+        &mut self,
+        handle: EventHandle,
+        expires: DateTime<Utc>,
+    ) -> Result<bool, Error> {
+        if !self.events.contains_key(&handle.0) {
+            return Ok(false);
+        }
+
+        sqlx::query!(
+            "UPDATE events
+            SET expires = $2
+            WHERE event_id = $1",
+            handle.0,
+            expires,
+        )
+        .execute(get_pool())
+        .await?;
+
+        Ok(self.move_to(handle, expires))
+    }
+
+    /// Moves an event in memory only, its old heap entry goes stale.
+    fn move_to(&mut self, handle: EventHandle, expires: DateTime<Utc>) -> bool {
+        match self.events.remove(&handle.0) {
+            Some(mut event) => {
+                event.expires = expires;
+                self.push(event);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_600_000_000 + seconds, 0).unwrap()
+    }
+
+    fn queue(events: &[(i64, i32)]) -> EventQueue<String> {
+        let mut queue = EventQueue::new();
+        for &(seconds, event_id) in events {
+            queue.push(Event {
+                expires: at(seconds),
+                event_id,
+                payload: "event".to_owned(),
+            });
+        }
+        queue
+    }
+
+    fn order(queue: &mut EventQueue<String>) -> Vec<i32> {
+        let mut order = Vec::new();
+        while let Some((handle, _)) = queue.pop() {
+            order.push(handle.0);
+        }
+        order
+    }
+
+    #[test]
+    fn pops_the_earliest_event_first() {
+        let mut queue = queue(&[(30, 1), (10, 2), (20, 3), (10, 4)]);
+        assert_eq!(queue.peek(), Some((at(10), 2)));
+        assert_eq!(order(&mut queue), vec![2, 4, 3, 1]);
+        assert_eq!(queue.peek(), None);
+    }
+
+    #[test]
+    fn skips_cancelled_events() {
+        let mut queue = queue(&[(10, 1), (20, 2), (30, 3)]);
+        queue.events.remove(&1);
+        queue.events.remove(&3);
+        assert_eq!(order(&mut queue), vec![2]);
+    }
+
+    #[test]
+    fn skips_the_old_entry_of_rescheduled_events() {
+        let mut queue = queue(&[(10, 1), (20, 2), (30, 3)]);
+        assert!(queue.move_to(EventHandle(1), at(40)));
+        assert!(queue.move_to(EventHandle(3), at(5)));
+        assert!(!queue.move_to(EventHandle(4), at(5)));
+        assert_eq!(order(&mut queue), vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn returns_popped_events_once() {
+        let mut queue = queue(&[(10, 1)]);
+        assert_eq!(queue.pop().map(|(handle, _)| handle), Some(EventHandle(1)));
+        assert!(queue.is_scheduled(&"event".to_owned()));
+        assert_eq!(queue.pop(), None);
 
-        next
+        // A failed event is retried by rescheduling it.
+        assert!(queue.move_to(EventHandle(1), at(20)));
+        assert_eq!(order(&mut queue), vec![1]);
     }
 
-    pub fn insert(&mut self, event: Event) {
-        let instant = event.expires_at();
-        if instant < self.next {
-            self.next = instant;
+    #[test]
+    fn drops_stale_entries_when_the_heap_grows() {
+        let mut queue = queue(&[(10, 1)]);
+        for seconds in 0..100 {
+            queue.move_to(EventHandle(1), at(seconds));
         }
-        
-        let index = self.deque.binary_search(&event).unwrap_or_else(|x| x);
-        self.deque.insert(index, event);
+        assert!(queue.heap.len() <= 2 * queue.events.len() + 16);
+        assert_eq!(queue.peek(), Some((at(99), 1)));
     }
 }
@@ -0,0 +1,164 @@
+use crate::{database::get_pool, model::user::UserId, Error};
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// Rejected actions within one burst after which the user gets flagged.
+const FLAG_THRESHOLD: u32 = 100;
+
+pub enum Verdict {
+    Allowed,
+    Limited,
+    /// The request was limited and the user sent suspiciously many of them.
+    Flagged(u32),
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+    rejected: u32,
+}
+
+impl TokenBucket {
+    fn new(burst: f64, now: Instant) -> Self {
+        Self {
+            tokens: burst,
+            updated: now,
+            rejected: 0,
+        }
+    }
+
+    fn refill(&mut self, rate: f64, burst: f64, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+
+        if self.tokens >= burst {
+            self.rejected = 0;
+        }
+    }
+}
+
+/// Per-user token buckets for one kind of client action.
+pub struct RateLimiter {
+    /// Sustained actions per second a user may take.
+    rate: f64,
+    /// Actions a user may take at once after being idle.
+    burst: f64,
+    buckets: HashMap<UserId, TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            buckets: HashMap::new(),
+        }
+    }
+
+    pub fn check(&mut self, user_id: UserId) -> Verdict {
+        self.check_at(user_id, Instant::now())
+    }
+
+    fn check_at(&mut self, user_id: UserId, now: Instant) -> Verdict {
+        let burst = self.burst;
+        let bucket = self
+            .buckets
+            .entry(user_id)
+            .or_insert_with(|| TokenBucket::new(burst, now));
+        bucket.refill(self.rate, burst, now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Verdict::Allowed
+        } else {
+            bucket.rejected += 1;
+            if bucket.rejected == FLAG_THRESHOLD {
+                Verdict::Flagged(bucket.rejected)
+            } else {
+                Verdict::Limited
+            }
+        }
+    }
+}
+
+/// Records a suspicious burst so it can be reviewed later.
+pub async fn flag(user_id: UserId, rejected: u32) -> Result<(), Error> {
+    sqlx::query!(
+        "INSERT INTO flags (user_id, rejected)
+        VALUES ($1, $2)",
+        user_id,
+        rejected as i32,
+    )
+    .execute(get_pool())
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn take(limiter: &mut RateLimiter, now: Instant, count: u32) -> u32 {
+        (0..count)
+            .filter(|_| matches!(limiter.check_at(1, now), Verdict::Allowed))
+            .count() as u32
+    }
+
+    #[test]
+    fn allows_a_burst_then_limits() {
+        let mut limiter = RateLimiter::new(2.0, 5.0);
+        let now = Instant::now();
+        assert_eq!(take(&mut limiter, now, 5), 5);
+        assert!(matches!(limiter.check_at(1, now), Verdict::Limited));
+        // Other users have their own bucket.
+        assert!(matches!(limiter.check_at(2, now), Verdict::Allowed));
+    }
+
+    #[test]
+    fn refills_at_the_rate_up_to_the_burst() {
+        let mut limiter = RateLimiter::new(2.0, 5.0);
+        let now = Instant::now();
+        assert_eq!(take(&mut limiter, now, 5), 5);
+
+        let later = now + Duration::from_secs(1);
+        assert_eq!(take(&mut limiter, later, 5), 2);
+
+        let idle = later + Duration::from_secs(60);
+        assert_eq!(take(&mut limiter, idle, 10), 5);
+    }
+
+    #[test]
+    fn flags_once_at_the_threshold() {
+        let mut limiter = RateLimiter::new(1.0, 1.0);
+        let now = Instant::now();
+        assert!(matches!(limiter.check_at(1, now), Verdict::Allowed));
+
+        for _ in 1..FLAG_THRESHOLD {
+            assert!(matches!(limiter.check_at(1, now), Verdict::Limited));
+        }
+        assert!(matches!(
+            limiter.check_at(1, now),
+            Verdict::Flagged(rejected) if rejected == FLAG_THRESHOLD
+        ));
+        assert!(matches!(limiter.check_at(1, now), Verdict::Limited));
+    }
+
+    #[test]
+    fn forgets_rejections_once_the_bucket_is_full() {
+        let mut limiter = RateLimiter::new(1.0, 1.0);
+        let now = Instant::now();
+        for _ in 0..FLAG_THRESHOLD {
+            limiter.check_at(1, now);
+        }
+
+        let later = now + Duration::from_secs(1);
+        assert!(matches!(limiter.check_at(1, later), Verdict::Allowed));
+        for _ in 1..FLAG_THRESHOLD {
+            assert!(matches!(limiter.check_at(1, later), Verdict::Limited));
+        }
+        assert!(matches!(limiter.check_at(1, later), Verdict::Flagged(_)));
+    }
+}
@@ -1,62 +1,537 @@
+use super::protocol::{Encoding, Envelope, Epoch, ErrorCode, RequestId, Tagged};
+use crate::model::{achievement::Achievement, session::PublicId, user::UserId};
+use chrono::{DateTime, Utc};
 use once_cell::sync::OnceCell;
-use tokio::sync::{mpsc, broadcast};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use crate::model::user::UserId;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
 
 pub static CLIENT_CREATOR: OnceCell<ClientCreator> = OnceCell::new();
 
-pub type ServerEndpont = (&'static broadcast::Sender<(Client, ServerMessage)>, mpsc::Receiver<(UserId, ClientMessage)>);
-pub type ClientEndpoint = (mpsc::Sender<(UserId, ClientMessage)>, broadcast::Receiver<(Client, ServerMessage)>);
+/// How many messages may queue up for a single connection before it counts as lagging.
+const CONNECTION_BUFFER: usize = 64;
+/// How many messages per user are kept for resuming.
+const HISTORY_SIZE: usize = 256;
+/// How long after disconnecting a user can still resume.
+const RESUME_WINDOW: Duration = Duration::from_secs(120);
 
+pub type ServerSender = &'static Registry;
+pub type ServerEndpont = (
+    ServerSender,
+    mpsc::Receiver<Request>,
+    mpsc::UnboundedReceiver<Presence>,
+);
+pub type ClientEndpoint = (mpsc::Sender<Request>, Connection);
+
+pub type ConnectionId = u64;
+pub type RoomId = i32;
+
+/// Hands out the channel to the game, which is replaced every time the game restarts.
 #[derive(Debug)]
-pub struct ClientCreator(mpsc::Sender<(UserId, ClientMessage)>, &'static broadcast::Sender<(Client, ServerMessage)>);
+pub struct ClientCreator(Mutex<mpsc::Sender<Request>>, &'static Registry);
 
 impl ClientCreator {
-    pub fn init() -> ServerEndpont {
+    pub fn init() -> &'static ClientCreator {
+        // Until the game is started, requests go nowhere.
+        let (client_tx, _) = mpsc::channel(1);
+        let (presence_tx, _) = mpsc::unbounded_channel();
+
+        let registry: &'static Registry = Box::leak(Box::new(Registry {
+            peers: Mutex::default(),
+            rooms: Mutex::default(),
+            next_id: AtomicU64::default(),
+            presence: Mutex::new(presence_tx),
+        }));
+
+        CLIENT_CREATOR
+            .set(ClientCreator(Mutex::new(client_tx), registry))
+            .unwrap();
+
+        CLIENT_CREATOR.get().unwrap()
+    }
+
+    /// Creates fresh channels for a new game, new connections are handed the new sender.
+    pub fn endpoint(&self) -> ServerEndpont {
         let (client_tx, rx) = mpsc::channel(16);
-        let (tx, _) = broadcast::channel(16);
+        let (presence_tx, presence_rx) = mpsc::unbounded_channel();
+
+        *self.0.lock().unwrap() = client_tx;
+        *self.1.presence.lock().unwrap() = presence_tx;
+
+        (self.1, rx, presence_rx)
+    }
+
+    pub fn create(&self, user_id: UserId, session: PublicId) -> ClientEndpoint {
+        (
+            self.0.lock().unwrap().clone(),
+            self.1.register(user_id, session),
+        )
+    }
+
+    pub fn registry(&self) -> &'static Registry {
+        self.1
+    }
+}
+
+/// Identifies the connection and request a reply belongs to.
+#[derive(Clone, Copy)]
+pub struct Origin {
+    pub user_id: UserId,
+    pub connection_id: ConnectionId,
+    pub id: Option<RequestId>,
+}
+
+pub struct Request {
+    pub origin: Origin,
+    pub message: Incoming,
+}
+
+/// A message the game loop handles itself, or one for the game rules,
+/// which is only decoded once the rules' message type is known.
+pub enum Incoming {
+    Common(ClientMessage),
+    Game { bytes: Vec<u8>, encoding: Encoding },
+}
+
+/// Sent to the game whenever a user opens their first or closes their last connection.
+#[derive(Debug)]
+pub enum Presence {
+    Online(UserId),
+    Offline(UserId),
+}
+
+/// The open connections of a user and the messages recently sent to all of them.
+#[derive(Debug)]
+struct Peer {
+    connections: HashMap<ConnectionId, mpsc::Sender<Envelope<Outgoing>>>,
+    /// The session every connection was opened with.
+    sessions: HashMap<ConnectionId, PublicId>,
+    history: VecDeque<Envelope<Outgoing>>,
+    /// Changes whenever the sequence starts over, so a client holding a sequence number
+    /// of an earlier one can't resume with it.
+    epoch: Epoch,
+    next_seq: u64,
+    disconnected: Option<Instant>,
+}
+
+impl Peer {
+    fn new() -> Self {
+        Self {
+            connections: HashMap::new(),
+            sessions: HashMap::new(),
+            history: VecDeque::new(),
+            epoch: rand::thread_rng().gen(),
+            next_seq: 0,
+            disconnected: Some(Instant::now()),
+        }
+    }
+
+    /// Sends the envelope to the given connections, dropping those that lag behind.
+    /// Returns `true` if this dropped the last connection of the user.
+    fn send(
+        &mut self,
+        user_id: UserId,
+        only: Option<ConnectionId>,
+        envelope: &Envelope<Outgoing>,
+    ) -> bool {
+        self.connections.retain(|connection_id, tx| {
+            if only.map_or(false, |only| only != *connection_id) {
+                return true;
+            }
+
+            match tx.try_send(envelope.clone()) {
+                Ok(()) => true,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    log::warn!(
+                        "connection {} of user {} lagged, closing it",
+                        connection_id,
+                        user_id
+                    );
+                    false
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+            }
+        });
+
+        self.disconnect_if_empty()
+    }
+
+    fn disconnect_if_empty(&mut self) -> bool {
+        if self.connections.is_empty() && self.disconnected.is_none() {
+            self.disconnected = Some(Instant::now());
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Keeps a sender for every open connection, grouped by user,
+/// and the room every user is in.
+#[derive(Debug)]
+pub struct Registry {
+    peers: Mutex<HashMap<UserId, Peer>>,
+    rooms: Mutex<HashMap<UserId, RoomId>>,
+    next_id: AtomicU64,
+    presence: Mutex<mpsc::UnboundedSender<Presence>>,
+}
+
+impl Registry {
+    fn register(&'static self, user_id: UserId, session: PublicId) -> Connection {
+        let connection_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel(CONNECTION_BUFFER);
+
+        let mut peers = self.peers.lock().unwrap();
+        let peer = peers.entry(user_id).or_insert_with(Peer::new);
+        peer.connections.insert(connection_id, tx);
+        peer.sessions.insert(connection_id, session);
+        if peer.disconnected.take().is_some() {
+            self.notify(Presence::Online(user_id));
+        }
+
+        Connection {
+            user_id,
+            connection_id,
+            registry: self,
+            rx,
+        }
+    }
+
+    fn notify(&self, presence: Presence) {
+        self.presence.lock().unwrap().send(presence).ok();
+    }
+
+    fn unregister(&self, user_id: UserId, connection_id: ConnectionId) {
+        let mut peers = self.peers.lock().unwrap();
+
+        if let Some(peer) = peers.get_mut(&user_id) {
+            peer.connections.remove(&connection_id);
+            peer.sessions.remove(&connection_id);
+            if peer.disconnect_if_empty() {
+                self.notify(Presence::Offline(user_id));
+            }
+        }
+    }
+
+    /// Closes the connections of a user that were opened with one of the sessions,
+    /// used when the sessions were revoked.
+    pub fn close_sessions(&self, user_id: UserId, sessions: &[PublicId]) {
+        let mut peers = self.peers.lock().unwrap();
+
+        if let Some(peer) = peers.get_mut(&user_id) {
+            let closed: Vec<ConnectionId> = peer
+                .sessions
+                .iter()
+                .filter(|(_, session)| sessions.contains(session))
+                .map(|(connection_id, _)| *connection_id)
+                .collect();
+            for connection_id in closed {
+                // Dropping the sender ends the connection, which then unregisters itself.
+                peer.connections.remove(&connection_id);
+                peer.sessions.remove(&connection_id);
+            }
+            if peer.disconnect_if_empty() {
+                self.notify(Presence::Offline(user_id));
+            }
+        }
+    }
+
+    /// Tells every connection to reconnect and closes it, and forgets all histories.
+    /// Used after the game restarted, since it can't continue where it left off.
+    pub fn reset(&self) {
+        let envelope = Envelope::new(None, ServerMessage::Reconnect.into());
+
+        for peer in self.peers.lock().unwrap().values_mut() {
+            for tx in peer.connections.values() {
+                tx.try_send(envelope.clone()).ok();
+            }
+            peer.connections.clear();
+            peer.sessions.clear();
+            peer.history.clear();
+            peer.epoch = rand::thread_rng().gen();
+            peer.next_seq = 0;
+            peer.disconnected.get_or_insert_with(Instant::now);
+        }
+    }
+
+    /// Forgets the history of users that have been gone for too long to resume.
+    pub fn prune(&self) {
+        self.peers.lock().unwrap().retain(|_, peer| {
+            peer.disconnected
+                .map_or(true, |disconnected| disconnected.elapsed() < RESUME_WINDOW)
+        });
+    }
+
+    /// Replays the messages a reconnecting client missed since `last_seq`.
+    /// Returns `false` if they are no longer available and the client needs a full resync.
+    pub fn resume(&self, origin: Origin, epoch: Epoch, last_seq: u64) -> bool {
+        let mut peers = self.peers.lock().unwrap();
+
+        let peer = match peers.get_mut(&origin.user_id) {
+            Some(peer) => peer,
+            None => return false,
+        };
+
+        if epoch != peer.epoch || last_seq >= peer.next_seq {
+            return false;
+        }
+
+        let oldest = peer
+            .history
+            .front()
+            .and_then(|envelope| envelope.seq)
+            .unwrap_or(peer.next_seq);
+        if oldest > last_seq + 1 {
+            return false;
+        }
+
+        let missed: Vec<Envelope<Outgoing>> = peer
+            .history
+            .iter()
+            .filter(|envelope| envelope.seq.map_or(false, |seq| seq > last_seq))
+            .cloned()
+            .collect();
+        for envelope in missed {
+            if peer.send(origin.user_id, Some(origin.connection_id), &envelope) {
+                self.notify(Presence::Offline(origin.user_id));
+            }
+        }
+
+        true
+    }
+
+    pub fn join_room(&self, user_id: UserId, room_id: RoomId) -> Option<RoomId> {
+        self.rooms.lock().unwrap().insert(user_id, room_id)
+    }
+
+    pub fn leave_room(&self, user_id: UserId) -> Option<RoomId> {
+        self.rooms.lock().unwrap().remove(&user_id)
+    }
+
+    pub fn room_of(&self, user_id: UserId) -> Option<RoomId> {
+        self.rooms.lock().unwrap().get(&user_id).copied()
+    }
+
+    pub fn room_members(&self, room_id: RoomId) -> Vec<UserId> {
+        self.rooms
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, room)| **room == room_id)
+            .map(|(user_id, _)| *user_id)
+            .collect()
+    }
+
+    /// Delivers a message to every connection of the users the client refers to.
+    ///
+    /// Each user gets their own sequence of these messages, recent ones are kept so
+    /// a reconnecting client can resume. Connections that can't keep up are dropped,
+    /// which closes their websocket so the client reconnects.
+    pub fn send(&self, client: Client, message: impl Into<Outgoing>) {
+        let message = message.into();
+        let room_members = match client {
+            Client::Room(room_id) => self.room_members(room_id),
+            _ => Vec::new(),
+        };
+
+        let mut peers = self.peers.lock().unwrap();
+
+        let user_ids: Vec<UserId> = match client {
+            Client::User(user_id) => vec![user_id],
+            Client::Room(_) => room_members,
+            Client::All => peers.keys().copied().collect(),
+        };
+
+        for user_id in user_ids {
+            if let Some(peer) = peers.get_mut(&user_id) {
+                let mut envelope = Envelope::new(None, message.clone());
+                envelope.seq = Some(peer.next_seq);
+                envelope.epoch = Some(peer.epoch);
+                peer.next_seq += 1;
+
+                peer.history.push_back(envelope.clone());
+                if peer.history.len() > HISTORY_SIZE {
+                    peer.history.pop_front();
+                }
+
+                if peer.send(user_id, None, &envelope) {
+                    self.notify(Presence::Offline(user_id));
+                }
+            }
+        }
+    }
+
+    /// Answers a request on the connection it came from, echoing its id.
+    /// Replies are not part of the user's sequence and can't be resumed.
+    pub fn reply(&self, origin: Origin, message: impl Into<Outgoing>) {
+        if let Some(peer) = self.peers.lock().unwrap().get_mut(&origin.user_id) {
+            if peer.send(
+                origin.user_id,
+                Some(origin.connection_id),
+                &Envelope::new(origin.id, message.into()),
+            ) {
+                self.notify(Presence::Offline(origin.user_id));
+            }
+        }
+    }
+}
 
-        let leaked_tx: &'static broadcast::Sender<(Client, ServerMessage)> = Box::leak(Box::new(tx));
+/// The receiving end of a single websocket connection.
+///
+/// Dropping it removes the connection from the registry.
+pub struct Connection {
+    user_id: UserId,
+    connection_id: ConnectionId,
+    registry: &'static Registry,
+    rx: mpsc::Receiver<Envelope<Outgoing>>,
+}
 
-        CLIENT_CREATOR.set(ClientCreator(client_tx, leaked_tx)).unwrap();
+impl Connection {
+    pub fn origin(&self, id: Option<RequestId>) -> Origin {
+        Origin {
+            user_id: self.user_id,
+            connection_id: self.connection_id,
+            id,
+        }
+    }
 
-        (leaked_tx, rx)
+    /// Returns `None` once the registry dropped this connection.
+    pub async fn recv(&mut self) -> Option<Envelope<Outgoing>> {
+        self.rx.recv().await
     }
+}
 
-    pub fn create(&self) -> ClientEndpoint {
-        (self.0.clone(), self.1.subscribe())
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.registry.unregister(self.user_id, self.connection_id);
     }
 }
 
 #[derive(Clone)]
 pub enum Client {
     User(UserId),
+    Room(RoomId),
     All,
 }
 
-impl Client {
-    pub fn includes(&self, user_id: UserId) -> bool {
-        match self {
-            Self::All => true,
-            Self::User(client) => *client == user_id
-        }
-    }
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScoreboardEntry {
+    pub rank: u32,
+    pub username: String,
+    pub score: i32,
+    pub online: bool,
+    pub last_seen: Option<DateTime<Utc>>,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
-pub struct ScoreboardEntry {
-    pub username: Option<String>,
-    pub score: Option<i32>,
+/// Where a chat message is sent, either to everyone or to the sender's room.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Channel {
+    #[default]
+    Global,
+    Room,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
 pub enum ServerMessage {
     UpdateScore(i32),
     UpdateScoreboard(Vec<ScoreboardEntry>),
+    RoomJoined(String),
+    RoomLeft,
+    UpdateRoomScoreboard(Vec<ScoreboardEntry>),
+    RateLimited,
+    Leaderboard(Vec<ScoreboardEntry>),
+    PresenceUpdate {
+        username: String,
+        online: bool,
+        last_seen: Option<DateTime<Utc>>,
+    },
+    AchievementUnlocked {
+        achievement: Achievement,
+        title: String,
+    },
+    /// The game restarted, the client has to connect again and resync.
+    Reconnect,
+    Chat {
+        username: String,
+        text: String,
+        sent_at: DateTime<Utc>,
+        channel: Channel,
+    },
+    Error {
+        id: Option<RequestId>,
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+/// A message for clients, either one of the game loop or one of the game rules.
+/// Both look the same on the wire, the registry holds the rules' messages serialized
+/// since it doesn't know their type.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Outgoing<U = serde_json::Value> {
+    Common(ServerMessage),
+    Game(U),
+}
+
+impl<U: Serialize> Outgoing<U> {
+    pub fn serialized(self) -> Outgoing {
+        match self {
+            Outgoing::Common(message) => Outgoing::Common(message),
+            Outgoing::Game(message) => Outgoing::Game(serde_json::to_value(message).unwrap()),
+        }
+    }
+}
+
+impl<U> From<ServerMessage> for Outgoing<U> {
+    fn from(message: ServerMessage) -> Self {
+        Outgoing::Common(message)
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
 pub enum ClientMessage {
-    Increment,
     Init,
-}
\ No newline at end of file
+    JoinRoom(String),
+    LeaveRoom,
+    RequestLeaderboard {
+        offset: usize,
+        limit: usize,
+    },
+    RequestRankAround {
+        radius: usize,
+    },
+    Resume {
+        epoch: Epoch,
+        last_seq: u64,
+    },
+    Chat {
+        text: String,
+        #[serde(default)]
+        channel: Channel,
+    },
+}
+
+impl Tagged for ClientMessage {
+    type Tag = ClientMessageType;
+}
+
+/// The `type` tags of [`ClientMessage`].
+#[derive(Deserialize)]
+pub enum ClientMessageType {
+    Init,
+    JoinRoom,
+    LeaveRoom,
+    RequestLeaderboard,
+    RequestRankAround,
+    Resume,
+    Chat,
+}
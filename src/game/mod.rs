@@ -1,69 +1,639 @@
+mod achievements;
+mod chat;
+mod clicker;
 mod event;
+mod limit;
 pub mod message;
+pub mod protocol;
+mod room;
+mod rules;
+mod scoreboard;
+mod shop;
 
-use crate::{database::get_pool, Error};
-use event::EventQueue;
-use message::*;
+pub use clicker::Clicker;
+pub use rules::GameRules;
+
+use crate::{
+    database::get_pool,
+    env::{SEASON_DAYS, TICK_RATE},
+    model::{season::Season, user::UserId},
+    regexes::ROOM_NAME,
+    shutdown_requested, Error, Shutdown,
+};
+use achievements::Achievements;
+use chrono::{DateTime, Utc};
+use event::{CommonPayload, EventQueue, Payload};
+use limit::{RateLimiter, Verdict};
 use log::*;
+use message::*;
+use protocol::ErrorCode;
+use rules::{Change, Outcome, View};
+use scoreboard::Scoreboard;
+use std::collections::HashSet;
+use std::time::Duration;
 
-pub async fn run((tx, mut rx): ServerEndpont) {
-    let queue = EventQueue::new();
-    while let Some((user_id, message)) = rx.recv().await {
-        match message {
-            ClientMessage::Increment => {
-                let score = sqlx::query!(
-                    "UPDATE states
-                    SET score = score + 1
-                    WHERE user_id = $1
-                    RETURNING score",
-                    user_id,
-                )
-                .fetch_one(get_pool())
-                .await
-                .unwrap()
-                .score;
-
-                let scoreboard = sqlx::query_as!(
-                    ScoreboardEntry,
-                    "SELECT username, score
-                    FROM states
-                    NATURAL JOIN users
-                    ORDER BY score DESC",
-                )
-                .fetch_all(get_pool())
-                .await
-                .unwrap();
-
-                tx.send((Client::User(user_id), ServerMessage::UpdateScore(score))).ok();
-                tx.send((Client::All, ServerMessage::UpdateScoreboard(scoreboard))).ok();
+/// How many players the global scoreboard shows.
+const SCOREBOARD_SIZE: usize = 10;
+/// How many players a client may request at once.
+const MAX_LEADERBOARD_SIZE: usize = 100;
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(5);
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+/// The longest time away players earn passive income for.
+const MAX_OFFLINE_HOURS: i64 = 8;
+const MIN_RETRY_DELAY: Duration = Duration::from_millis(100);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(10);
+/// How long an event whose handling failed waits before it is handled again.
+const EVENT_RETRY_DELAY: Duration = Duration::from_secs(10);
+/// How long to wait before restarting a crashed game, so a crash loop doesn't spin.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Awaits a fallible database operation until it succeeds, logging every failure
+/// and waiting longer before each attempt. Only for things the game can't go on without.
+macro_rules! retry {
+    ($operation:expr) => {{
+        let mut delay = MIN_RETRY_DELAY;
+        loop {
+            match $operation.await {
+                Ok(value) => break value,
+                Err(err) => {
+                    error!(
+                        "{} failed, retrying in {:?}: {:?}",
+                        stringify!($operation),
+                        delay,
+                        err
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+            }
+        }
+    }};
+}
+
+fn season_duration() -> chrono::Duration {
+    chrono::Duration::days(*SEASON_DAYS)
+}
+
+fn expires_after(duration: Duration) -> DateTime<Utc> {
+    Utc::now() + chrono::Duration::from_std(duration).unwrap()
+}
+
+struct Game<R: GameRules> {
+    tx: ServerSender,
+    queue: EventQueue<Payload<R::Payload>>,
+    rules: R,
+    scoreboard: Scoreboard,
+    achievements: Achievements,
+    chat_limiter: RateLimiter,
+    changed_rooms: HashSet<RoomId>,
+}
+
+/// Runs the game and restarts it from the last snapshot with fresh channels whenever it panics.
+/// Clients that were connected to the crashed game are told to reconnect.
+/// Returns once the game stopped after a shutdown, with all its state written.
+pub async fn supervise<R, F>(client_creator: &'static ClientCreator, rules: F, shutdown: Shutdown)
+where
+    R: GameRules + Send + 'static,
+    F: Fn() -> R,
+{
+    let mut endpoint = client_creator.endpoint();
+    loop {
+        match tokio::spawn(run(endpoint, rules(), shutdown.clone())).await {
+            Ok(()) => break,
+            Err(err) => {
+                error!("game crashed, restarting it: {:?}", err);
+                // Clients that reconnect right away must reach the new game, not the crashed one.
+                // Their requests wait in the new channels until it is running.
+                endpoint = client_creator.endpoint();
+                client_creator.registry().reset();
+                tokio::time::sleep(RESTART_DELAY).await;
+            }
+        }
+    }
+}
 
+async fn run<R: GameRules>(
+    (tx, mut rx, mut presence): ServerEndpont,
+    mut rules: R,
+    shutdown: Shutdown,
+) {
+    for (user_id, room_id) in retry!(room::memberships()) {
+        tx.join_room(user_id, room_id);
+    }
+    retry!(rules.load());
+
+    let mut game = Game {
+        tx,
+        queue: retry!(EventQueue::load()),
+        rules,
+        scoreboard: retry!(Scoreboard::load()),
+        achievements: retry!(Achievements::load()),
+        chat_limiter: RateLimiter::new(chat::RATE, chat::BURST),
+        changed_rooms: HashSet::new(),
+    };
+
+    let outcome = game.rules.start(&View {
+        scoreboard: &game.scoreboard,
+        queue: &game.queue,
+    });
+    game.apply(None, outcome).await;
+
+    let season = match retry!(Season::current()) {
+        Some(season) => season,
+        None => retry!(Season::start(season_duration())),
+    };
+    // The end of a season that was ended by hand would end the running one.
+    let stale: Vec<_> = game
+        .queue
+        .scheduled()
+        .filter(|(_, payload)| match payload {
+            Payload::Common(CommonPayload::SeasonEnd(season_id)) => *season_id != season.season_id,
+            Payload::Game(_) => false,
+        })
+        .map(|(handle, _)| handle)
+        .collect();
+    for handle in stale {
+        retry!(game.queue.cancel(handle));
+    }
+    let season_end = Payload::Common(CommonPayload::SeasonEnd(season.season_id));
+    if !game.queue.is_scheduled(&season_end) {
+        retry!(game.queue.insert(season.ends, season_end.clone()));
+    }
+
+    // A tick rate of zero would divide by zero, at least one tick per second is sent.
+    let mut ticks = tokio::time::interval(Duration::from_secs(1) / (*TICK_RATE).max(1));
+    let mut snapshots = tokio::time::interval(SNAPSHOT_INTERVAL);
+    let mut reloads = tokio::time::interval(RELOAD_INTERVAL);
+    let stopped = shutdown_requested(shutdown);
+    tokio::pin!(stopped);
+
+    loop {
+        tokio::select! {
+            _ = &mut stopped => {
+                info!("stopping the game");
+                break;
+            },
+            message = rx.recv() => match message {
+                Some(request) => {
+                    let origin = request.origin;
+                    if let Err(err) = game.handle_request(request).await {
+                        error!("request of user {} failed: {:?}", origin.user_id, err);
+                        game.tx.reply(
+                            origin,
+                            ServerMessage::Error {
+                                id: origin.id,
+                                code: ErrorCode::Unavailable,
+                                message: "the request failed, try again later".to_owned(),
+                            },
+                        );
+                    }
+                }
+                None => break,
+            },
+            Some(change) = presence.recv() => {
+                if let Err(err) = game.handle_presence(change).await {
+                    error!("presence change failed: {:?}", err);
+                }
+            },
+            (handle, payload) = game.queue.next() => match game.handle_event(&payload).await {
+                Ok(next) => retry!(game.queue.complete(handle, next.clone())),
+                Err(err) => {
+                    error!(
+                        "event {:?} failed, retrying in {:?}: {:?}",
+                        payload, EVENT_RETRY_DELAY, err
+                    );
+                    retry!(game
+                        .queue
+                        .reschedule(handle, expires_after(EVENT_RETRY_DELAY)));
+                }
             },
-            ClientMessage::Init => {
-                let score = sqlx::query!(
-                    "SELECT score
-                    FROM states
-                    WHERE user_id = $1",
+            _ = ticks.tick() => game.tick(),
+            _ = snapshots.tick() => {
+                if let Err(err) = game.snapshot().await {
+                    error!("snapshot failed, retrying with the next one: {:?}", err);
+                }
+                game.tx.prune();
+                game.check_top();
+            },
+            _ = reloads.tick() => {
+                if let Err(err) = game.reload().await {
+                    error!("reloading the scoreboard failed: {:?}", err);
+                }
+            },
+        }
+    }
+
+    retry!(game.snapshot());
+}
+
+impl<R: GameRules> Game<R> {
+    /// Writes all state that changed since the last snapshot in one transaction.
+    /// If this fails, the changes are kept and written with the next snapshot.
+    async fn snapshot(&mut self) -> Result<(), Error> {
+        let mut transaction = get_pool().begin().await?;
+        self.scoreboard.write(&mut transaction).await?;
+        self.rules.write(&mut transaction).await?;
+        self.achievements.write(&mut transaction).await?;
+        transaction.commit().await?;
+
+        for (user_id, score) in self.scoreboard.clean() {
+            self.tx
+                .send(Client::User(user_id), ServerMessage::UpdateScore(score));
+            if let Some(room_id) = self.tx.room_of(user_id) {
+                self.changed_rooms.insert(room_id);
+            }
+        }
+        self.rules.clean();
+        self.achievements.clean();
+
+        Ok(())
+    }
+
+    async fn reload(&mut self) -> Result<(), Error> {
+        self.snapshot().await?;
+        self.scoreboard.reload().await
+    }
+
+    /// Carries out what the rules decided, replies go to `origin` if there is one.
+    async fn apply(&mut self, origin: Option<Origin>, outcome: Outcome<R::Payload, R::Update>) {
+        let tx = self.tx;
+
+        for change in outcome.changes {
+            match change {
+                Change::AddScore { user_id, delta } => {
+                    if let Some(score) = self.scoreboard.add(user_id, delta) {
+                        tx.send(Client::User(user_id), ServerMessage::UpdateScore(score));
+                        for message in self.achievements.check_score(user_id, score) {
+                            tx.send(Client::User(user_id), message);
+                        }
+                    }
+                    if let Some(room_id) = tx.room_of(user_id) {
+                        self.changed_rooms.insert(room_id);
+                    }
+                }
+                Change::Unlock {
                     user_id,
-                )
-                .fetch_one(get_pool())
-                .await
-                .unwrap()
-                .score;
+                    achievement,
+                } => {
+                    if let Some(message) = self.achievements.unlock(user_id, achievement) {
+                        tx.send(Client::User(user_id), message);
+                    }
+                }
+                Change::Flag { user_id, rejected } => {
+                    if let Err(err) = limit::flag(user_id, rejected).await {
+                        error!("flagging user {} failed: {:?}", user_id, err);
+                    }
+                }
+                Change::Schedule { after, payload } => {
+                    retry!(self
+                        .queue
+                        .insert_after(after, Payload::Game(payload.clone())));
+                }
+            }
+        }
+
+        if let Some(origin) = origin {
+            for message in outcome.replies {
+                tx.reply(origin, message.serialized());
+            }
+            for (code, message) in outcome.errors {
+                tx.reply(
+                    origin,
+                    ServerMessage::Error {
+                        id: origin.id,
+                        code,
+                        message,
+                    },
+                );
+            }
+        }
+        for (client, message) in outcome.messages {
+            tx.send(client, message.serialized());
+        }
+    }
+
+    /// Sends at most one update per scoreboard that changed since the last tick.
+    fn tick(&mut self) {
+        if self.scoreboard.take_changed() {
+            self.tx.send(
+                Client::All,
+                ServerMessage::UpdateScoreboard(self.scoreboard.top(SCOREBOARD_SIZE)),
+            );
+        }
+
+        for room_id in self.changed_rooms.drain() {
+            self.tx.send(
+                Client::Room(room_id),
+                ServerMessage::UpdateRoomScoreboard(
+                    self.scoreboard.group(&self.tx.room_members(room_id)),
+                ),
+            );
+        }
+    }
+
+    fn check_top(&mut self) {
+        let top = self.scoreboard.top_ids(SCOREBOARD_SIZE);
+        for (user_id, message) in self.achievements.check_top(&top) {
+            self.tx.send(Client::User(user_id), message);
+        }
+    }
+
+    async fn handle_presence(&mut self, change: Presence) -> Result<(), Error> {
+        let (user_id, online) = match change {
+            Presence::Online(user_id) => (user_id, true),
+            Presence::Offline(user_id) => (user_id, false),
+        };
+
+        self.scoreboard.ensure(user_id).await?;
+        if let Some(update) = self.scoreboard.set_online(user_id, online) {
+            self.tx.send(Client::All, update);
+        }
+        if let Some(room_id) = self.tx.room_of(user_id) {
+            self.changed_rooms.insert(room_id);
+        }
+
+        Ok(())
+    }
+
+    /// Pays a player that came back for the time they were away,
+    /// before the client gets sent its score.
+    fn claim_offline(&mut self, origin: Origin) {
+        let view = View {
+            scoreboard: &self.scoreboard,
+            queue: &self.queue,
+        };
+        let points_per_second = self.rules.passive_income(&view, origin.user_id);
+        if let Some((away, points)) = self.scoreboard.claim_offline(
+            origin.user_id,
+            points_per_second,
+            chrono::Duration::hours(MAX_OFFLINE_HOURS),
+        ) {
+            if points > 0 {
+                if let Some(update) = self.rules.offline_earnings(away, points) {
+                    self.tx.reply(origin, Outgoing::Game(update).serialized());
+                }
+            }
+        }
+    }
+
+    async fn handle_request(&mut self, Request { origin, message }: Request) -> Result<(), Error> {
+        let tx = self.tx;
+        let user_id = origin.user_id;
+        self.scoreboard.ensure(user_id).await?;
+        if let Some(message) = self.achievements.check_played(user_id).await? {
+            tx.send(Client::User(user_id), message);
+        }
+
+        let message = match message {
+            Incoming::Common(message) => message,
+            Incoming::Game { bytes, encoding } => {
+                match protocol::decode::<R::Message>(&bytes, encoding) {
+                    Ok(envelope) => {
+                        let view = View {
+                            scoreboard: &self.scoreboard,
+                            queue: &self.queue,
+                        };
+                        let outcome = self.rules.handle_message(&view, user_id, envelope.message);
+                        self.apply(Some(origin), outcome).await;
+                    }
+                    Err(err) => {
+                        debug!("rejected message from user {}: {}", user_id, err.message);
+                        tx.reply(origin, err.into_message());
+                    }
+                }
+                return Ok(());
+            }
+        };
+
+        match message {
+            ClientMessage::Init => self.init(origin).await?,
+            ClientMessage::Resume { epoch, last_seq } => {
+                if !tx.resume(origin, epoch, last_seq) {
+                    self.init(origin).await?;
+                }
+            }
+            ClientMessage::JoinRoom(name) => {
+                if !ROOM_NAME.is_match(&name) {
+                    tx.reply(
+                        origin,
+                        ServerMessage::Error {
+                            id: origin.id,
+                            code: ErrorCode::MalformedMessage,
+                            message: "room names consist of 2 to 32 letters, digits or underscores"
+                                .to_owned(),
+                        },
+                    );
+                    return Ok(());
+                }
+
+                let room_id = room::join(user_id, &name).await?;
+                if let Some(previous) = tx.join_room(user_id, room_id) {
+                    self.changed_rooms.insert(previous);
+                }
+
+                tx.reply(origin, ServerMessage::RoomJoined(name));
+                for message in chat::history(Some(room_id)).await? {
+                    tx.reply(origin, message);
+                }
+                self.changed_rooms.insert(room_id);
+            }
+            ClientMessage::LeaveRoom => {
+                if let Some(room_id) = tx.room_of(user_id) {
+                    room::leave(user_id).await?;
+                    tx.leave_room(user_id);
+                    tx.reply(origin, ServerMessage::RoomLeft);
+                    self.changed_rooms.insert(room_id);
+                }
+            }
+            ClientMessage::RequestLeaderboard { offset, limit } => {
+                tx.reply(
+                    origin,
+                    ServerMessage::Leaderboard(
+                        self.scoreboard
+                            .page(offset, limit.min(MAX_LEADERBOARD_SIZE)),
+                    ),
+                );
+            }
+            ClientMessage::RequestRankAround { radius } => {
+                tx.reply(
+                    origin,
+                    ServerMessage::Leaderboard(
+                        self.scoreboard
+                            .around(user_id, radius.min(MAX_LEADERBOARD_SIZE / 2)),
+                    ),
+                );
+            }
+            ClientMessage::Chat { text, channel } => self.chat(origin, text, channel).await?,
+        }
+
+        Ok(())
+    }
+
+    async fn chat(&mut self, origin: Origin, text: String, channel: Channel) -> Result<(), Error> {
+        let tx = self.tx;
+        let user_id = origin.user_id;
+
+        let text = text.trim();
+        if text.is_empty() {
+            return Ok(());
+        }
+        match self.chat_limiter.check(user_id) {
+            Verdict::Allowed => {}
+            Verdict::Limited => {
+                tx.reply(origin, ServerMessage::RateLimited);
+                return Ok(());
+            }
+            Verdict::Flagged(rejected) => {
+                warn!(
+                    "flagging user {} for {} rejected chat messages",
+                    user_id, rejected
+                );
+                tx.reply(origin, ServerMessage::RateLimited);
+                return limit::flag(user_id, rejected).await;
+            }
+        }
+        if text.chars().count() > chat::MAX_LENGTH {
+            tx.reply(
+                origin,
+                ServerMessage::Error {
+                    id: origin.id,
+                    code: ErrorCode::MessageTooLong,
+                    message: format!(
+                        "chat messages may be at most {} characters long",
+                        chat::MAX_LENGTH
+                    ),
+                },
+            );
+            return Ok(());
+        }
+
+        let (room_id, client) = match channel {
+            Channel::Global => (None, Client::All),
+            Channel::Room => match tx.room_of(user_id) {
+                Some(room_id) => (Some(room_id), Client::Room(room_id)),
+                None => {
+                    tx.reply(
+                        origin,
+                        ServerMessage::Error {
+                            id: origin.id,
+                            code: ErrorCode::NotInRoom,
+                            message: "join a room to chat in it".to_owned(),
+                        },
+                    );
+                    return Ok(());
+                }
+            },
+        };
+
+        let username = match self.scoreboard.username(user_id) {
+            Some(username) => username.to_owned(),
+            None => return Ok(()),
+        };
+        let text = chat::filter(text);
+        let sent_at = chat::save(user_id, room_id, &text).await?;
+
+        tx.send(
+            client,
+            ServerMessage::Chat {
+                username,
+                text,
+                sent_at,
+                channel,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Sends everything a freshly connected client needs to display.
+    async fn init(&mut self, origin: Origin) -> Result<(), Error> {
+        let tx = self.tx;
+        let user_id = origin.user_id;
+
+        self.claim_offline(origin);
+
+        if let Some(score) = self.scoreboard.score(user_id) {
+            for message in self.achievements.check_score(user_id, score) {
+                tx.send(Client::User(user_id), message);
+            }
+            tx.reply(origin, ServerMessage::UpdateScore(score));
+        }
+        tx.reply(
+            origin,
+            ServerMessage::UpdateScoreboard(self.scoreboard.top(SCOREBOARD_SIZE)),
+        );
+        for message in chat::history(None).await? {
+            tx.reply(origin, message);
+        }
+        if let Some(room_id) = tx.room_of(user_id) {
+            tx.reply(
+                origin,
+                ServerMessage::RoomJoined(room::name(room_id).await?),
+            );
+            tx.reply(
+                origin,
+                ServerMessage::UpdateRoomScoreboard(
+                    self.scoreboard.group(&tx.room_members(room_id)),
+                ),
+            );
+            for message in chat::history(Some(room_id)).await? {
+                tx.reply(origin, message);
+            }
+        }
+
+        let view = View {
+            scoreboard: &self.scoreboard,
+            queue: &self.queue,
+        };
+        for message in self.rules.init(&view, user_id) {
+            tx.reply(origin, message.serialized());
+        }
+
+        Ok(())
+    }
+
+    /// Returns the events that follow the handled one, they are stored when it is removed.
+    async fn handle_event(
+        &mut self,
+        payload: &Payload<R::Payload>,
+    ) -> Result<Vec<(DateTime<Utc>, Payload<R::Payload>)>, Error> {
+        match payload {
+            Payload::Common(CommonPayload::SeasonEnd(season_id)) => {
+                info!("season {} ended", season_id);
+
+                self.snapshot().await?;
+                let season = Season::end(*season_id, season_duration()).await?;
+                self.scoreboard.reload().await?;
+
+                self.tx.send(Client::All, ServerMessage::UpdateScore(0));
+
+                Ok(vec![(
+                    season.ends,
+                    Payload::Common(CommonPayload::SeasonEnd(season.season_id)),
+                )])
+            }
+            Payload::Game(payload) => {
+                let view = View {
+                    scoreboard: &self.scoreboard,
+                    queue: &self.queue,
+                };
+                let mut outcome = self.rules.handle_event(&view, payload);
 
-                let scoreboard = sqlx::query_as!(
-                    ScoreboardEntry,
-                    "SELECT username, score
-                    FROM states
-                    NATURAL JOIN users
-                    ORDER BY score DESC",
-                )
-                .fetch_all(get_pool())
-                .await
-                .unwrap();
+                let mut next = Vec::new();
+                let mut changes = Vec::new();
+                for change in outcome.changes {
+                    match change {
+                        Change::Schedule { after, payload } => {
+                            next.push((expires_after(after), Payload::Game(payload)))
+                        }
+                        change => changes.push(change),
+                    }
+                }
+                outcome.changes = changes;
+                self.apply(None, outcome).await;
 
-                tx.send((Client::User(user_id), ServerMessage::UpdateScore(score))).ok();
-                tx.send((Client::All, ServerMessage::UpdateScoreboard(scoreboard))).ok();
+                Ok(next)
             }
         }
     }
-}    
\ No newline at end of file
+}
@@ -0,0 +1,351 @@
+use super::message::{ClientMessage, Incoming, Outgoing, ServerMessage};
+use serde::{
+    de::{
+        value::{self, StrDeserializer},
+        DeserializeOwned, IntoDeserializer,
+    },
+    Deserialize, Serialize,
+};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Chosen by the client to match replies to its requests.
+pub type RequestId = u64;
+/// Identifies a sequence of messages, sequence numbers are only comparable within one.
+pub type Epoch = u32;
+
+/// Every message on the wire looks like `{ "v": 1, "id": …, "type": …, "data": … }`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub v: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<RequestId>,
+    /// Numbers the messages sent to a user so a reconnecting client can resume.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub epoch: Option<Epoch>,
+    #[serde(flatten)]
+    pub message: T,
+}
+
+impl<T> Envelope<T> {
+    pub fn new(id: Option<RequestId>, message: T) -> Self {
+        Self {
+            v: PROTOCOL_VERSION,
+            id,
+            seq: None,
+            epoch: None,
+            message,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    MalformedMessage,
+    UnknownType,
+    UnsupportedVersion,
+    MessageTooLong,
+    NotInRoom,
+    InsufficientFunds,
+    /// The server couldn't handle the request right now, it may be sent again.
+    Unavailable,
+}
+
+pub struct ProtocolError {
+    pub id: Option<RequestId>,
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ProtocolError {
+    pub fn into_message(self) -> ServerMessage {
+        ServerMessage::Error {
+            id: self.id,
+            code: self.code,
+            message: self.message,
+        }
+    }
+}
+
+/// How messages are serialized, negotiated through the websocket subprotocol.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Encoding {
+    /// Picks the first supported subprotocol from the `Sec-WebSocket-Protocol` header.
+    pub fn negotiate(header: &str) -> Option<Self> {
+        header
+            .split(',')
+            .map(str::trim)
+            .find_map(|protocol| match protocol {
+                "json" => Some(Self::Json),
+                "msgpack" => Some(Self::MessagePack),
+                "cbor" => Some(Self::Cbor),
+                _ => None,
+            })
+    }
+
+    pub fn subprotocol(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::MessagePack => "msgpack",
+            Self::Cbor => "cbor",
+        }
+    }
+
+    /// Whether messages are sent as binary instead of text frames.
+    pub fn is_binary(self) -> bool {
+        self != Self::Json
+    }
+
+    fn deserialize<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Self::Json => serde_json::from_slice(bytes).map_err(|err| err.to_string()),
+            Self::MessagePack => rmp_serde::from_slice(bytes).map_err(|err| err.to_string()),
+            Self::Cbor => serde_cbor::from_slice(bytes).map_err(|err| err.to_string()),
+        }
+    }
+
+    fn serialize<T: Serialize>(self, value: &T) -> Vec<u8> {
+        match self {
+            Self::Json => serde_json::to_vec(value).unwrap(),
+            Self::MessagePack => rmp_serde::to_vec_named(value).unwrap(),
+            Self::Cbor => serde_cbor::to_vec(value).unwrap(),
+        }
+    }
+}
+
+/// A message type whose `type` tag can be checked on its own, so that an unknown type
+/// is told apart from a malformed message the same way in every encoding.
+pub trait Tagged: DeserializeOwned {
+    /// A fieldless enum with one variant per message type.
+    type Tag: DeserializeOwned;
+}
+
+/// The part of an envelope that can be read even if the rest is invalid.
+#[derive(Deserialize)]
+struct Header {
+    v: Option<u32>,
+    id: Option<RequestId>,
+    #[serde(rename = "type")]
+    tag: Option<String>,
+}
+
+/// Checks the version and returns the request id and the message type.
+fn decode_header(
+    bytes: &[u8],
+    encoding: Encoding,
+) -> Result<(Option<RequestId>, String), ProtocolError> {
+    let header: Header = encoding
+        .deserialize(bytes)
+        .map_err(|message| ProtocolError {
+            id: None,
+            code: ErrorCode::MalformedMessage,
+            message,
+        })?;
+
+    if header.v != Some(PROTOCOL_VERSION) {
+        return Err(ProtocolError {
+            id: header.id,
+            code: ErrorCode::UnsupportedVersion,
+            message: format!("expected protocol version {}", PROTOCOL_VERSION),
+        });
+    }
+
+    let tag = header.tag.ok_or_else(|| ProtocolError {
+        id: header.id,
+        code: ErrorCode::MalformedMessage,
+        message: "missing message type".to_owned(),
+    })?;
+
+    Ok((header.id, tag))
+}
+
+fn is_known<M: Tagged>(tag: &str) -> bool {
+    let deserializer: StrDeserializer<value::Error> = tag.into_deserializer();
+    M::Tag::deserialize(deserializer).is_ok()
+}
+
+fn decode_body<M: DeserializeOwned>(
+    id: Option<RequestId>,
+    bytes: &[u8],
+    encoding: Encoding,
+) -> Result<Envelope<M>, ProtocolError> {
+    encoding
+        .deserialize(bytes)
+        .map_err(|message| ProtocolError {
+            id,
+            code: ErrorCode::MalformedMessage,
+            message,
+        })
+}
+
+pub fn decode<M: Tagged>(bytes: &[u8], encoding: Encoding) -> Result<Envelope<M>, ProtocolError> {
+    let (id, tag) = decode_header(bytes, encoding)?;
+    if !is_known::<M>(&tag) {
+        return Err(ProtocolError {
+            id,
+            code: ErrorCode::UnknownType,
+            message: format!("unknown message type {:?}", tag),
+        });
+    }
+
+    decode_body(id, bytes, encoding)
+}
+
+/// Decodes the messages the game loop handles itself. Messages of any other type
+/// are passed on as they are, they are decoded once the game rules know their type.
+pub fn decode_request(
+    bytes: &[u8],
+    encoding: Encoding,
+) -> Result<Envelope<Incoming>, ProtocolError> {
+    let (id, tag) = decode_header(bytes, encoding)?;
+    if is_known::<ClientMessage>(&tag) {
+        let envelope = decode_body::<ClientMessage>(id, bytes, encoding)?;
+        return Ok(Envelope::new(id, Incoming::Common(envelope.message)));
+    }
+
+    Ok(Envelope::new(
+        id,
+        Incoming::Game {
+            bytes: bytes.to_vec(),
+            encoding,
+        },
+    ))
+}
+
+pub fn encode(envelope: &Envelope<Outgoing>, encoding: Encoding) -> Vec<u8> {
+    encoding.serialize(envelope)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const ENCODINGS: [Encoding; 3] = [Encoding::Json, Encoding::MessagePack, Encoding::Cbor];
+
+    fn decode_value(
+        value: serde_json::Value,
+        encoding: Encoding,
+    ) -> Result<Envelope<ClientMessage>, ProtocolError> {
+        decode(&encoding.serialize(&value), encoding)
+    }
+
+    #[test]
+    fn decodes_known_types() {
+        for encoding in ENCODINGS.iter().copied() {
+            let envelope = decode_value(json!({ "v": 1, "id": 7, "type": "Init" }), encoding)
+                .ok()
+                .unwrap();
+            assert_eq!(envelope.id, Some(7));
+            assert!(matches!(envelope.message, ClientMessage::Init));
+        }
+    }
+
+    #[test]
+    fn reports_unknown_types() {
+        for encoding in ENCODINGS.iter().copied() {
+            let err = decode_value(json!({ "v": 1, "id": 7, "type": "Fly" }), encoding)
+                .err()
+                .unwrap();
+            assert_eq!(err.id, Some(7));
+            assert!(matches!(err.code, ErrorCode::UnknownType), "{:?}", encoding);
+        }
+    }
+
+    #[test]
+    fn reports_malformed_messages() {
+        for encoding in ENCODINGS.iter().copied() {
+            let err = decode_value(
+                json!({ "v": 1, "id": 7, "type": "JoinRoom", "data": 5 }),
+                encoding,
+            )
+            .err()
+            .unwrap();
+            assert_eq!(err.id, Some(7));
+            assert!(
+                matches!(err.code, ErrorCode::MalformedMessage),
+                "{:?}",
+                encoding
+            );
+
+            let err = decode_value(json!({ "v": 1, "id": 7 }), encoding)
+                .err()
+                .unwrap();
+            assert!(
+                matches!(err.code, ErrorCode::MalformedMessage),
+                "{:?}",
+                encoding
+            );
+        }
+    }
+
+    #[test]
+    fn reports_unsupported_versions() {
+        for encoding in ENCODINGS.iter().copied() {
+            let err = decode_value(json!({ "v": 2, "type": "Init" }), encoding)
+                .err()
+                .unwrap();
+            assert!(
+                matches!(err.code, ErrorCode::UnsupportedVersion),
+                "{:?}",
+                encoding
+            );
+        }
+    }
+
+    #[test]
+    fn passes_on_game_messages() {
+        for encoding in ENCODINGS.iter().copied() {
+            let bytes = encoding
+                .serialize(&json!({ "v": 1, "id": 7, "type": "Chat", "data": { "text": "hi" } }));
+            let envelope = decode_request(&bytes, encoding).ok().unwrap();
+            assert!(matches!(
+                envelope.message,
+                Incoming::Common(ClientMessage::Chat { .. })
+            ));
+
+            let bytes = encoding.serialize(&json!({ "v": 1, "id": 7, "type": "Fly" }));
+            let envelope = decode_request(&bytes, encoding).ok().unwrap();
+            assert_eq!(envelope.id, Some(7));
+            match envelope.message {
+                Incoming::Game {
+                    bytes: passed,
+                    encoding: passed_encoding,
+                } => {
+                    assert_eq!(passed, bytes);
+                    assert_eq!(passed_encoding, encoding);
+                }
+                Incoming::Common(_) => panic!("{:?}", encoding),
+            }
+        }
+    }
+
+    #[test]
+    fn encodes_game_messages_like_common_ones() {
+        let common = Envelope::new(Some(7), Outgoing::Common(ServerMessage::UpdateScore(5)));
+        let game = Envelope::new(
+            Some(7),
+            Outgoing::Game(json!({ "type": "UpdateScore", "data": 5 })),
+        );
+        for encoding in ENCODINGS.iter().copied() {
+            let decoded: serde_json::Value =
+                encoding.deserialize(&encode(&common, encoding)).unwrap();
+            assert_eq!(
+                decoded,
+                json!({ "v": 1, "id": 7, "type": "UpdateScore", "data": 5 })
+            );
+            let game_decoded: serde_json::Value =
+                encoding.deserialize(&encode(&game, encoding)).unwrap();
+            assert_eq!(game_decoded, decoded, "{:?}", encoding);
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use super::message::RoomId;
+use crate::{database::get_pool, model::user::UserId, Error};
+
+/// Returns the room every user is currently in.
+pub async fn memberships() -> Result<Vec<(UserId, RoomId)>, Error> {
+    Ok(sqlx::query!(
+        "SELECT user_id, room_id
+        FROM room_members",
+    )
+    .fetch_all(get_pool())
+    .await?
+    .into_iter()
+    .map(|row| (row.user_id, row.room_id))
+    .collect())
+}
+
+/// Moves the user into the room with the given name, creating it if necessary.
+/// Rooms that are left empty are deleted, together with their chat history.
+pub async fn join(user_id: UserId, name: &str) -> Result<RoomId, Error> {
+    let mut transaction = get_pool().begin().await?;
+
+    let room_id = sqlx::query!(
+        "INSERT INTO rooms (name)
+        VALUES ($1)
+        ON CONFLICT (name) DO UPDATE
+        SET name = EXCLUDED.name
+        RETURNING room_id",
+        name,
+    )
+    .fetch_one(&mut transaction)
+    .await?
+    .room_id;
+
+    sqlx::query!(
+        "INSERT INTO room_members (user_id, room_id)
+        VALUES ($1, $2)
+        ON CONFLICT (user_id) DO UPDATE
+        SET room_id = EXCLUDED.room_id",
+        user_id,
+        room_id,
+    )
+    .execute(&mut transaction)
+    .await?;
+
+    sqlx::query!(
+        "DELETE FROM rooms
+        WHERE NOT EXISTS (
+            SELECT 1
+            FROM room_members
+            WHERE room_members.room_id = rooms.room_id
+        )",
+    )
+    .execute(&mut transaction)
+    .await?;
+
+    transaction.commit().await?;
+
+    Ok(room_id)
+}
+
+/// Removes the user from their room and deletes the room once it is empty,
+/// together with its chat history.
+pub async fn leave(user_id: UserId) -> Result<(), Error> {
+    let mut transaction = get_pool().begin().await?;
+
+    sqlx::query!(
+        "DELETE FROM room_members
+        WHERE user_id = $1",
+        user_id,
+    )
+    .execute(&mut transaction)
+    .await?;
+
+    sqlx::query!(
+        "DELETE FROM rooms
+        WHERE NOT EXISTS (
+            SELECT 1
+            FROM room_members
+            WHERE room_members.room_id = rooms.room_id
+        )",
+    )
+    .execute(&mut transaction)
+    .await?;
+
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+pub async fn name(room_id: RoomId) -> Result<String, Error> {
+    Ok(sqlx::query!(
+        "SELECT name
+        FROM rooms
+        WHERE room_id = $1",
+        room_id,
+    )
+    .fetch_one(get_pool())
+    .await?
+    .name)
+}
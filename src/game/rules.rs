@@ -0,0 +1,165 @@
+use super::event::{EventQueue, Payload};
+use super::message::{Client, Outgoing};
+use super::protocol::{ErrorCode, Tagged};
+use super::scoreboard::Scoreboard;
+use crate::{
+    model::{achievement::Achievement, user::UserId},
+    Error,
+};
+use futures::future::{self, BoxFuture, FutureExt};
+use serde::{de::DeserializeOwned, Serialize};
+use sqlx::{Postgres, Transaction};
+use std::fmt::Debug;
+use std::time::Duration;
+
+/// Read access to the state the game loop owns.
+pub struct View<'a, P> {
+    pub scoreboard: &'a Scoreboard,
+    pub queue: &'a EventQueue<Payload<P>>,
+}
+
+impl<P: PartialEq> View<'_, P> {
+    /// Whether an event of the rules with this payload is scheduled.
+    pub fn is_scheduled(&self, payload: &P) -> bool {
+        self.queue.payloads().any(|scheduled| match scheduled {
+            Payload::Game(scheduled) => scheduled == payload,
+            Payload::Common(_) => false,
+        })
+    }
+}
+
+/// A state change requested by the rules, the game loop applies and persists it.
+pub enum Change<P> {
+    /// Adds to a player's score, the player gets sent the new score.
+    AddScore {
+        user_id: UserId,
+        delta: i32,
+    },
+    /// Unlocks an achievement the game loop can't tell from the score alone.
+    Unlock {
+        user_id: UserId,
+        achievement: Achievement,
+    },
+    /// Records a suspicious player so it can be reviewed later.
+    Flag {
+        user_id: UserId,
+        rejected: u32,
+    },
+    /// Schedules an event, when handling an event it is stored in the same transaction
+    /// that removes the handled one.
+    Schedule {
+        after: Duration,
+        payload: P,
+    },
+}
+
+/// Everything the rules want to happen in response to a message or event.
+pub struct Outcome<P, U> {
+    pub changes: Vec<Change<P>>,
+    /// Messages for the connection that sent the request, ignored for events.
+    pub replies: Vec<Outgoing<U>>,
+    /// Errors for the connection that sent the request, they echo the request's id.
+    pub errors: Vec<(ErrorCode, String)>,
+    pub messages: Vec<(Client, Outgoing<U>)>,
+}
+
+impl<P, U> Outcome<P, U> {
+    pub fn new() -> Self {
+        Self {
+            changes: Vec::new(),
+            replies: Vec::new(),
+            errors: Vec::new(),
+            messages: Vec::new(),
+        }
+    }
+
+    pub fn change(mut self, change: Change<P>) -> Self {
+        self.changes.push(change);
+        self
+    }
+
+    pub fn reply(mut self, message: impl Into<Outgoing<U>>) -> Self {
+        self.replies.push(message.into());
+        self
+    }
+
+    pub fn error(mut self, code: ErrorCode, message: String) -> Self {
+        self.errors.push((code, message));
+        self
+    }
+
+    pub fn send(mut self, client: Client, message: impl Into<Outgoing<U>>) -> Self {
+        self.messages.push((client, message.into()));
+        self
+    }
+}
+
+impl<P, U> Default for Outcome<P, U> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The game specific behavior. Connections, rooms, chat, leaderboards and seasons
+/// are handled by the game loop, everything else is passed on to the rules.
+pub trait GameRules {
+    /// The messages clients send to play, any type the game loop doesn't handle itself
+    /// is decoded as one.
+    type Message: Tagged + Send + 'static;
+    /// The messages the rules send to clients. They are sent next to the game loop's own
+    /// [`ServerMessage`](super::message::ServerMessage)s, so they need type tags of their own.
+    type Update: Serialize + Send + 'static;
+    /// The events the rules schedule. They are stored next to the game loop's own events,
+    /// so they must not serialize like a [`CommonPayload`](super::event::CommonPayload).
+    type Payload: Debug + Clone + PartialEq + Serialize + DeserializeOwned + Send + Sync + 'static;
+
+    /// Loads the state the rules keep themselves, called whenever the game starts.
+    fn load(&mut self) -> BoxFuture<'_, Result<(), Error>> {
+        future::ok(()).boxed()
+    }
+
+    /// Writes the state that changed since the last snapshot as part of it,
+    /// so it is committed together with the scores.
+    fn write<'a>(
+        &'a self,
+        _transaction: &'a mut Transaction<'_, Postgres>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        future::ok(()).boxed()
+    }
+
+    /// Forgets the changes once a snapshot containing them has been committed.
+    fn clean(&mut self) {}
+
+    /// Called once on startup, for example to schedule the first events.
+    fn start(&mut self, _view: &View<Self::Payload>) -> Outcome<Self::Payload, Self::Update> {
+        Outcome::new()
+    }
+
+    fn handle_message(
+        &mut self,
+        view: &View<Self::Payload>,
+        user_id: UserId,
+        message: Self::Message,
+    ) -> Outcome<Self::Payload, Self::Update>;
+
+    fn handle_event(
+        &mut self,
+        view: &View<Self::Payload>,
+        payload: &Self::Payload,
+    ) -> Outcome<Self::Payload, Self::Update>;
+
+    /// Points per second a player earns without playing, paid out for the time they were away.
+    fn passive_income(&self, _view: &View<Self::Payload>, _user_id: UserId) -> f64 {
+        0.0
+    }
+
+    /// Tells a player that came back what they earned while they were away.
+    fn offline_earnings(&self, _away: chrono::Duration, _points: i32) -> Option<Self::Update> {
+        None
+    }
+
+    /// Additional messages a freshly connected client needs to display.
+    fn init(&self, _view: &View<Self::Payload>, _user_id: UserId) -> Vec<Outgoing<Self::Update>> {
+        Vec::new()
+    }
+}
@@ -0,0 +1,541 @@
+use super::message::{ScoreboardEntry, ServerMessage};
+use crate::{database::get_pool, model::user::UserId, Error};
+use chrono::{DateTime, Duration, Utc};
+use log::*;
+use sqlx::{Postgres, Transaction};
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+struct Player {
+    username: String,
+    score: i32,
+    last_seen: Option<DateTime<Utc>>,
+    /// The last seen time as of the last committed snapshot.
+    stored_last_seen: Option<DateTime<Utc>>,
+}
+
+/// Offline earnings that haven't been written yet.
+struct Claim {
+    /// The stored last seen time they were paid from, the claim only counts if it is still stored.
+    since: DateTime<Utc>,
+    points: i32,
+}
+
+/// All scores kept in memory and ranked, changes are written back in batches.
+pub struct Scoreboard {
+    players: HashMap<UserId, Player>,
+    ranking: BTreeSet<(Reverse<i32>, UserId)>,
+    /// Players whose score or last seen time changed since the last write.
+    dirty: HashSet<UserId>,
+    /// Kept across reloads, it is only updated through presence changes.
+    online: HashSet<UserId>,
+    /// Players that came back and haven't been paid for their time away yet.
+    returned: HashSet<UserId>,
+    claims: HashMap<UserId, Claim>,
+    /// Claims the last write found to be paid already, they are taken back once it is committed.
+    rejected: Vec<UserId>,
+    changed: bool,
+}
+
+impl Scoreboard {
+    fn new() -> Self {
+        Self {
+            players: HashMap::new(),
+            ranking: BTreeSet::new(),
+            dirty: HashSet::new(),
+            online: HashSet::new(),
+            returned: HashSet::new(),
+            claims: HashMap::new(),
+            rejected: Vec::new(),
+            changed: true,
+        }
+    }
+
+    pub async fn load() -> Result<Self, Error> {
+        let mut scoreboard = Self::new();
+        scoreboard.reload().await?;
+
+        Ok(scoreboard)
+    }
+
+    /// Replaces all players with the database contents to pick up new, renamed and deleted users.
+    /// Pending changes have to be written first, otherwise they are lost.
+    pub async fn reload(&mut self) -> Result<(), Error> {
+        let rows = sqlx::query!(
+            "SELECT user_id, username, score, last_seen
+            FROM states
+            NATURAL JOIN users",
+        )
+        .fetch_all(get_pool())
+        .await?;
+
+        self.players.clear();
+        self.ranking.clear();
+        for row in rows {
+            self.insert(row.user_id, row.username, row.score, row.last_seen);
+        }
+        self.changed = true;
+
+        Ok(())
+    }
+
+    /// Loads a single player that isn't known yet, for example after signing up.
+    pub async fn ensure(&mut self, user_id: UserId) -> Result<(), Error> {
+        if self.players.contains_key(&user_id) {
+            return Ok(());
+        }
+
+        if let Some(row) = sqlx::query!(
+            "SELECT username, score, last_seen
+            FROM states
+            NATURAL JOIN users
+            WHERE user_id = $1",
+            user_id,
+        )
+        .fetch_optional(get_pool())
+        .await?
+        {
+            self.insert(user_id, row.username, row.score, row.last_seen);
+            self.changed = true;
+        }
+
+        Ok(())
+    }
+
+    fn insert(
+        &mut self,
+        user_id: UserId,
+        username: String,
+        score: i32,
+        last_seen: Option<DateTime<Utc>>,
+    ) {
+        self.ranking.insert((Reverse(score), user_id));
+        self.players.insert(
+            user_id,
+            Player {
+                username,
+                score,
+                last_seen,
+                stored_last_seen: last_seen,
+            },
+        );
+    }
+
+    pub fn username(&self, user_id: UserId) -> Option<&str> {
+        self.players
+            .get(&user_id)
+            .map(|player| player.username.as_str())
+    }
+
+    pub fn score(&self, user_id: UserId) -> Option<i32> {
+        self.players.get(&user_id).map(|player| player.score)
+    }
+
+    /// Applies a delta to a player's score and returns the new score.
+    pub fn add(&mut self, user_id: UserId, delta: i32) -> Option<i32> {
+        let player = self.players.get_mut(&user_id)?;
+
+        self.ranking.remove(&(Reverse(player.score), user_id));
+        player.score = player.score.saturating_add(delta);
+        self.ranking.insert((Reverse(player.score), user_id));
+
+        self.dirty.insert(user_id);
+        self.changed = true;
+
+        Some(player.score)
+    }
+
+    /// Turns players that are already ordered by score into entries.
+    /// Players with equal scores share a rank, the next rank is skipped accordingly.
+    fn entries<I>(&self, user_ids: I, position: usize, rank: u32) -> Vec<ScoreboardEntry>
+    where
+        I: IntoIterator<Item = UserId>,
+    {
+        let mut entries: Vec<ScoreboardEntry> = Vec::new();
+
+        for (index, user_id) in user_ids.into_iter().enumerate() {
+            let player = &self.players[&user_id];
+            let rank = match entries.last() {
+                Some(last) if last.score == player.score => last.rank,
+                Some(_) => (position + index) as u32 + 1,
+                None => rank,
+            };
+
+            entries.push(ScoreboardEntry {
+                rank,
+                username: player.username.clone(),
+                score: player.score,
+                online: self.online.contains(&user_id),
+                last_seen: player.last_seen,
+            });
+        }
+
+        entries
+    }
+
+    /// The rank of a score, one more than the number of players with a higher score.
+    fn rank_of(&self, score: i32) -> u32 {
+        self.ranking.range(..(Reverse(score), UserId::MIN)).count() as u32 + 1
+    }
+
+    /// The players from position `offset` onwards, at most `limit` of them.
+    pub fn page(&self, offset: usize, limit: usize) -> Vec<ScoreboardEntry> {
+        let mut players = self.ranking.iter().skip(offset).take(limit).peekable();
+
+        let rank = match players.peek() {
+            Some((Reverse(score), _)) => self.rank_of(*score),
+            None => return Vec::new(),
+        };
+
+        self.entries(players.map(|(_, user_id)| *user_id), offset, rank)
+    }
+
+    /// The ids of the best `limit` players.
+    pub fn top_ids(&self, limit: usize) -> Vec<UserId> {
+        self.ranking
+            .iter()
+            .take(limit)
+            .map(|(_, user_id)| *user_id)
+            .collect()
+    }
+
+    /// The best `limit` players.
+    pub fn top(&self, limit: usize) -> Vec<ScoreboardEntry> {
+        self.page(0, limit)
+    }
+
+    /// The players up to `radius` positions above and below the given player.
+    pub fn around(&self, user_id: UserId, radius: usize) -> Vec<ScoreboardEntry> {
+        match self.score(user_id) {
+            Some(score) => {
+                let position = self.ranking.range(..(Reverse(score), user_id)).count();
+                self.page(position.saturating_sub(radius), 2 * radius + 1)
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// The given players, ordered and ranked by score among themselves.
+    pub fn group(&self, user_ids: &[UserId]) -> Vec<ScoreboardEntry> {
+        let mut ranked: Vec<(Reverse<i32>, UserId)> = user_ids
+            .iter()
+            .filter_map(|user_id| self.score(*user_id).map(|score| (Reverse(score), *user_id)))
+            .collect();
+        ranked.sort();
+
+        self.entries(ranked.into_iter().map(|(_, user_id)| user_id), 0, 1)
+    }
+
+    pub fn is_online(&self, user_id: UserId) -> bool {
+        self.online.contains(&user_id)
+    }
+
+    /// Marks a player as online or offline, going offline sets their last seen time.
+    /// Returns the presence update to broadcast.
+    pub fn set_online(&mut self, user_id: UserId, online: bool) -> Option<ServerMessage> {
+        if online {
+            if self.online.insert(user_id) {
+                self.returned.insert(user_id);
+            }
+        } else {
+            self.online.remove(&user_id);
+            self.returned.remove(&user_id);
+        }
+
+        let player = self.players.get_mut(&user_id)?;
+        if !online {
+            player.last_seen = Some(Utc::now());
+            self.dirty.insert(user_id);
+        }
+        self.changed = true;
+
+        Some(ServerMessage::PresenceUpdate {
+            username: player.username.clone(),
+            online,
+            last_seen: player.last_seen,
+        })
+    }
+
+    /// Pays a player that came back for the time since they were last seen, at most `max_away`.
+    /// Returns how long the player was away and how many points they earned.
+    /// Players are paid once per return, time away that no snapshot recorded yet isn't paid.
+    ///
+    /// Paying out moves the last seen time. The new score and last seen time are written together
+    /// and only if the stored last seen time is still the one of the last snapshot,
+    /// so the same time away is never paid twice.
+    pub fn claim_offline(
+        &mut self,
+        user_id: UserId,
+        points_per_second: f64,
+        max_away: Duration,
+    ) -> Option<(Duration, i32)> {
+        if !self.returned.remove(&user_id) {
+            return None;
+        }
+        let player = self.players.get_mut(&user_id)?;
+        let last_seen = player.last_seen?;
+        let stored_last_seen = player.stored_last_seen?;
+
+        let now = Utc::now();
+        let away = (now - last_seen).max(Duration::zero()).min(max_away);
+        let points = (away.num_seconds() as f64 * points_per_second).min(i32::MAX as f64) as i32;
+
+        player.last_seen = Some(now);
+        let claim = self.claims.entry(user_id).or_insert(Claim {
+            since: stored_last_seen,
+            points: 0,
+        });
+        claim.points = claim.points.saturating_add(points);
+        self.dirty.insert(user_id);
+        self.add(user_id, points);
+
+        Some((away, points))
+    }
+
+    /// Returns whether the ranking changed since the last call.
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::replace(&mut self.changed, false)
+    }
+
+    /// Writes all changed scores and last seen times as part of a snapshot.
+    pub async fn write(
+        &mut self,
+        transaction: &mut Transaction<'_, Postgres>,
+    ) -> Result<(), Error> {
+        self.rejected.clear();
+        if self.dirty.is_empty() {
+            return Ok(());
+        }
+
+        let mut user_ids = Vec::new();
+        let mut since = Vec::new();
+        let mut last_seen = Vec::new();
+        for (user_id, claim) in &self.claims {
+            if let Some(player_last_seen) = self
+                .players
+                .get(user_id)
+                .and_then(|player| player.last_seen)
+            {
+                user_ids.push(*user_id);
+                since.push(claim.since);
+                last_seen.push(player_last_seen);
+            }
+        }
+
+        let claimed: HashSet<UserId> = sqlx::query!(
+            "UPDATE users
+            SET last_seen = data.last_seen
+            FROM UNNEST($1::INTEGER[], $2::TIMESTAMPTZ[], $3::TIMESTAMPTZ[])
+                AS data(user_id, since, last_seen)
+            WHERE users.user_id = data.user_id
+            AND users.last_seen = data.since
+            RETURNING users.user_id",
+            &user_ids,
+            &since,
+            &last_seen,
+        )
+        .fetch_all(&mut *transaction)
+        .await?
+        .into_iter()
+        .map(|row| row.user_id)
+        .collect();
+        self.rejected = user_ids
+            .into_iter()
+            .filter(|user_id| !claimed.contains(user_id))
+            .collect();
+
+        let (user_ids, scores): (Vec<UserId>, Vec<i32>) = self
+            .dirty
+            .iter()
+            .filter_map(|user_id| {
+                let score = self.score(*user_id)?;
+                match self.claims.get(user_id) {
+                    Some(claim) if self.rejected.contains(user_id) => {
+                        Some((*user_id, take_back(score, claim.points)))
+                    }
+                    _ => Some((*user_id, score)),
+                }
+            })
+            .unzip();
+
+        sqlx::query!(
+            "UPDATE states
+            SET score = data.score
+            FROM UNNEST($1::INTEGER[], $2::INTEGER[]) AS data(user_id, score)
+            WHERE states.user_id = data.user_id",
+            &user_ids,
+            &scores,
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        let (user_ids, last_seen): (Vec<UserId>, Vec<DateTime<Utc>>) = self
+            .dirty
+            .iter()
+            .filter(|user_id| !self.claims.contains_key(user_id))
+            .filter_map(|user_id| {
+                self.players
+                    .get(user_id)
+                    .and_then(|player| player.last_seen)
+                    .map(|last_seen| (*user_id, last_seen))
+            })
+            .unzip();
+
+        sqlx::query!(
+            "UPDATE users
+            SET last_seen = data.last_seen
+            FROM UNNEST($1::INTEGER[], $2::TIMESTAMPTZ[]) AS data(user_id, last_seen)
+            WHERE users.user_id = data.user_id",
+            &user_ids,
+            &last_seen,
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Forgets the changes once a snapshot containing them has been committed.
+    /// Returns the players whose offline earnings were taken back with their corrected scores.
+    pub fn clean(&mut self) -> Vec<(UserId, i32)> {
+        let rejected = std::mem::take(&mut self.rejected);
+        for user_id in self.dirty.drain() {
+            if let Some(player) = self.players.get_mut(&user_id) {
+                if !rejected.contains(&user_id) {
+                    player.stored_last_seen = player.last_seen;
+                }
+            }
+        }
+
+        let mut corrected = Vec::new();
+        for user_id in rejected {
+            if let (Some(claim), Some(score)) = (self.claims.get(&user_id), self.score(user_id)) {
+                warn!(
+                    "offline earnings of user {} were already paid, taking back {} points",
+                    user_id, claim.points
+                );
+                let delta = take_back(score, claim.points) - score;
+                if let Some(score) = self.add(user_id, delta) {
+                    corrected.push((user_id, score));
+                }
+                // The corrected score was written already.
+                self.dirty.remove(&user_id);
+            }
+        }
+        self.claims.clear();
+
+        corrected
+    }
+}
+
+/// Takes back points that were paid twice, a score never drops below zero because of it.
+fn take_back(score: i32, points: i32) -> i32 {
+    score.saturating_sub(points).max(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Alice leads, Bob and Carol are tied for second, Dave is last.
+    fn scoreboard() -> Scoreboard {
+        let mut scoreboard = Scoreboard::new();
+        for (user_id, username, score) in [
+            (1, "alice", 50),
+            (2, "bob", 30),
+            (3, "carol", 30),
+            (4, "dave", 10),
+        ]
+        .iter()
+        .copied()
+        {
+            scoreboard.insert(user_id, username.to_owned(), score, None);
+        }
+        scoreboard
+    }
+
+    fn ranks(entries: &[ScoreboardEntry]) -> Vec<(&str, u32)> {
+        entries
+            .iter()
+            .map(|entry| (entry.username.as_str(), entry.rank))
+            .collect()
+    }
+
+    #[test]
+    fn ties_share_a_rank() {
+        let scoreboard = scoreboard();
+        assert_eq!(
+            ranks(&scoreboard.top(10)),
+            vec![("alice", 1), ("bob", 2), ("carol", 2), ("dave", 4)]
+        );
+        assert_eq!(scoreboard.rank_of(30), 2);
+        assert_eq!(scoreboard.rank_of(10), 4);
+    }
+
+    #[test]
+    fn pages_starting_within_a_tie() {
+        let scoreboard = scoreboard();
+        assert_eq!(
+            ranks(&scoreboard.page(2, 2)),
+            vec![("carol", 2), ("dave", 4)]
+        );
+        assert_eq!(ranks(&scoreboard.page(3, 10)), vec![("dave", 4)]);
+        assert!(scoreboard.page(4, 10).is_empty());
+    }
+
+    #[test]
+    fn around_stops_at_the_edges() {
+        let scoreboard = scoreboard();
+        assert_eq!(
+            ranks(&scoreboard.around(1, 1)),
+            vec![("alice", 1), ("bob", 2), ("carol", 2)]
+        );
+        assert_eq!(
+            ranks(&scoreboard.around(4, 1)),
+            vec![("carol", 2), ("dave", 4)]
+        );
+        assert_eq!(
+            ranks(&scoreboard.around(3, 1)),
+            vec![("bob", 2), ("carol", 2), ("dave", 4)]
+        );
+        assert!(scoreboard.around(5, 1).is_empty());
+    }
+
+    #[test]
+    fn pays_returning_players_once() {
+        let mut scoreboard = Scoreboard::new();
+        let last_seen = Utc::now() - Duration::hours(1);
+        scoreboard.insert(1, "alice".to_owned(), 10, Some(last_seen));
+
+        assert_eq!(scoreboard.claim_offline(1, 1.0, Duration::hours(8)), None);
+
+        scoreboard.set_online(1, true);
+        let (away, points) = scoreboard
+            .claim_offline(1, 1.0, Duration::minutes(30))
+            .unwrap();
+        assert_eq!(away, Duration::minutes(30));
+        assert_eq!(points, 30 * 60);
+        assert_eq!(scoreboard.score(1), Some(10 + 30 * 60));
+        assert_eq!(scoreboard.claims[&1].since, last_seen);
+
+        // Another connection of the same player isn't paid again.
+        scoreboard.set_online(1, true);
+        assert_eq!(scoreboard.claim_offline(1, 1.0, Duration::hours(8)), None);
+    }
+
+    #[test]
+    fn takes_back_at_most_the_whole_score() {
+        assert_eq!(take_back(100, 30), 70);
+        assert_eq!(take_back(20, 30), 0);
+        assert_eq!(take_back(i32::MIN, 1), 0);
+    }
+
+    #[test]
+    fn group_ranks_among_its_members() {
+        let scoreboard = scoreboard();
+        assert_eq!(
+            ranks(&scoreboard.group(&[4, 3, 2])),
+            vec![("bob", 1), ("carol", 1), ("dave", 3)]
+        );
+    }
+}
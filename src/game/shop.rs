@@ -0,0 +1,188 @@
+use crate::{database::get_pool, model::user::UserId, Error};
+use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, Transaction};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Item {
+    /// Each one makes a click worth one more point.
+    Multiplier,
+    /// Each one generates points over time.
+    AutoClicker,
+}
+
+impl Item {
+    pub const ALL: [Item; 2] = [Item::Multiplier, Item::AutoClicker];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Item::Multiplier => "multiplier",
+            Item::AutoClicker => "auto_clicker",
+        }
+    }
+
+    fn parse(item: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|known| known.as_str() == item)
+    }
+
+    fn base_price(self) -> i64 {
+        match self {
+            Item::Multiplier => 50,
+            Item::AutoClicker => 200,
+        }
+    }
+
+    /// Every item costs half as much again as the previous one of its kind.
+    pub fn price(self, owned: i32) -> i32 {
+        (0..owned).fold(self.base_price(), |price, _| {
+            (price * 3 / 2).min(i32::MAX as i64)
+        }) as i32
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InventoryEntry {
+    pub item: Item,
+    pub owned: i32,
+    /// What the next one costs.
+    pub price: i32,
+}
+
+/// The upgrades every player owns, kept in memory and written back in snapshots.
+pub struct Shop {
+    inventories: HashMap<UserId, HashMap<Item, i32>>,
+    dirty: HashSet<(UserId, Item)>,
+}
+
+impl Shop {
+    pub fn new() -> Self {
+        Self {
+            inventories: HashMap::new(),
+            dirty: HashSet::new(),
+        }
+    }
+
+    pub async fn load() -> Result<Self, Error> {
+        let rows = sqlx::query!(
+            "SELECT user_id, item, count
+            FROM upgrades",
+        )
+        .fetch_all(get_pool())
+        .await?;
+
+        let mut inventories: HashMap<UserId, HashMap<Item, i32>> = HashMap::new();
+        for row in rows {
+            match Item::parse(&row.item) {
+                Some(item) => {
+                    inventories
+                        .entry(row.user_id)
+                        .or_default()
+                        .insert(item, row.count);
+                }
+                None => log::warn!("ignoring unknown item {:?}", row.item),
+            }
+        }
+
+        Ok(Self {
+            inventories,
+            dirty: HashSet::new(),
+        })
+    }
+
+    pub fn owned(&self, user_id: UserId, item: Item) -> i32 {
+        self.inventories
+            .get(&user_id)
+            .and_then(|inventory| inventory.get(&item))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// All players that own at least one of the item, with how many they own.
+    pub fn owners(&self, item: Item) -> impl Iterator<Item = (UserId, i32)> + '_ {
+        self.inventories
+            .iter()
+            .filter_map(move |(user_id, inventory)| {
+                inventory
+                    .get(&item)
+                    .filter(|count| **count > 0)
+                    .map(|count| (*user_id, *count))
+            })
+    }
+
+    pub fn inventory(&self, user_id: UserId) -> Vec<InventoryEntry> {
+        Item::ALL
+            .iter()
+            .map(|item| {
+                let owned = self.owned(user_id, *item);
+                InventoryEntry {
+                    item: *item,
+                    owned,
+                    price: item.price(owned),
+                }
+            })
+            .collect()
+    }
+
+    /// Adds an item to the inventory, the price has to be deducted from the score
+    /// before the next snapshot so both are written in one transaction.
+    pub fn add(&mut self, user_id: UserId, item: Item) {
+        *self
+            .inventories
+            .entry(user_id)
+            .or_default()
+            .entry(item)
+            .or_default() += 1;
+        self.dirty.insert((user_id, item));
+    }
+
+    /// Writes all changed inventories as part of a snapshot.
+    /// Inventories of users that were deleted in the meantime are skipped.
+    pub async fn write(&self, transaction: &mut Transaction<'_, Postgres>) -> Result<(), Error> {
+        if self.dirty.is_empty() {
+            return Ok(());
+        }
+
+        let mut user_ids = Vec::new();
+        let mut items = Vec::new();
+        let mut counts = Vec::new();
+        for (user_id, item) in &self.dirty {
+            user_ids.push(*user_id);
+            items.push(item.as_str().to_owned());
+            counts.push(self.owned(*user_id, *item));
+        }
+
+        sqlx::query!(
+            "INSERT INTO upgrades (user_id, item, count)
+            SELECT data.user_id, data.item, data.count
+            FROM UNNEST($1::INTEGER[], $2::VARCHAR[], $3::INTEGER[]) AS data(user_id, item, count)
+            WHERE EXISTS (
+                SELECT 1
+                FROM users
+                WHERE users.user_id = data.user_id
+            )
+            ON CONFLICT (user_id, item) DO UPDATE
+            SET count = EXCLUDED.count",
+            &user_ids,
+            &items,
+            &counts,
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Forgets the changes once a snapshot containing them has been committed.
+    pub fn clean(&mut self) {
+        self.dirty.clear();
+    }
+}
+
+impl Default for Shop {
+    fn default() -> Self {
+        Self::new()
+    }
+}
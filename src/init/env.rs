@@ -1,7 +1,15 @@
+use std::{fmt, str::FromStr};
+
 macro_rules! static_env {
-    ( $( $i:ident : $t:ty ),* $(,)? ) => {
+    ( $( $i:ident : $t:ty $( = $d:expr )? ),* $(,)? ) => {
         $(
-            pub static $i: once_cell::sync::Lazy<$t> = once_cell::sync::Lazy::new(|| std::env::var(stringify!($i)).unwrap().parse::<$t>().unwrap());
+            pub static $i: once_cell::sync::Lazy<$t> = once_cell::sync::Lazy::new(|| {
+                std::env::var(stringify!($i))
+                    .ok()
+                    .map(|var| var.parse::<$t>().unwrap())
+                    $( .or_else(|| Some($d)) )?
+                    .unwrap()
+            });
         )*
     };
 }
@@ -9,4 +17,46 @@ macro_rules! static_env {
 static_env! {
     DATABASE_URL: String,
     PORT: u16,
+    TICK_RATE: u32 = 10,
+    SEASON_DAYS: i64 = 30,
+    CHAT_BLOCKLIST: String = String::new(),
+    COOKIE_SECURE: bool = false,
+    COOKIE_SAME_SITE: SameSite = SameSite::Lax,
+    BEHIND_PROXY: bool = false,
+    TOTP_ISSUER: String = String::from("User Website"),
+}
+
+/// The `SameSite` attribute of cookies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    /// Browsers only accept this on secure cookies.
+    None,
+}
+
+impl FromStr for SameSite {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "Strict" => Ok(Self::Strict),
+            "Lax" => Ok(Self::Lax),
+            "None" => Ok(Self::None),
+            _ => Err(format!("expected Strict, Lax or None, got {:?}", value)),
+        }
+    }
+}
+
+impl fmt::Display for SameSite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// Panics on settings that don't work together, so they are noticed on startup.
+pub fn check() {
+    if *COOKIE_SAME_SITE == SameSite::None && !*COOKIE_SECURE {
+        panic!("COOKIE_SAME_SITE=None requires COOKIE_SECURE");
+    }
 }
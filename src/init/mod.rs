@@ -5,5 +5,6 @@ pub mod regexes;
 pub async fn init() {
     dotenv::dotenv().ok();
     pretty_env_logger::init();
+    env::check();
     database::init().await;
 }
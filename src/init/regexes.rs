@@ -10,4 +10,5 @@ static_regex! {
     USERNAME_CHARS: r"^[a-zA-Z0-9_]*$",
     USERNAME_LENGTH: r"^.{2,16}$",
     PASSWORD_LENGTH: r"^.{4,32}$",
+    ROOM_NAME: r"^[a-zA-Z0-9_]{2,32}$",
 }
@@ -1,6 +1,3 @@
-#![feature(vecdeque_binary_search)]
-#![feature(duration_constants)]
-
 mod error;
 mod game;
 mod init;
@@ -11,22 +8,57 @@ pub use error::Error;
 pub use init::*;
 
 use env::PORT;
+use tokio::sync::watch;
 use warp::Filter;
 
+/// Tells everything that runs until the server stops to finish up.
+pub type Shutdown = watch::Receiver<bool>;
+
+/// Resolves once the server is asked to stop.
+pub async fn shutdown_requested(mut shutdown: Shutdown) {
+    // An error means the sender is gone, which only happens when stopping as well.
+    while !*shutdown.borrow() {
+        if shutdown.changed().await.is_err() {
+            break;
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     init().await;
 
-    let server_endpoint = game::message::ClientCreator::init();
+    let client_creator = game::message::ClientCreator::init();
 
     let routes = routes::serve()
         .or(warp::fs::dir("public"))
         .recover(error::handle_rejection)
         .with(warp::log("server"));
 
+    let (stop, shutdown) = watch::channel(false);
+    tokio::spawn(async move {
+        match tokio::signal::ctrl_c().await {
+            Ok(()) => {
+                log::info!("shutting down");
+                stop.send(true).ok();
+            }
+            Err(err) => {
+                log::error!("failed to listen for ctrl-c: {}", err);
+                // Dropping the sender would count as a shutdown.
+                futures::future::pending::<()>().await;
+            }
+        }
+    });
+
+    let (_, server) = warp::serve(routes).bind_with_graceful_shutdown(
+        ([127, 0, 0, 1], *PORT),
+        shutdown_requested(shutdown.clone()),
+    );
+
     tokio::join!(
-        warp::serve(routes).run(([127, 0, 0, 1], *PORT)),
-        game::run(server_endpoint),
+        server,
+        game::supervise(client_creator, game::Clicker::new, shutdown.clone()),
+        model::session::clean_up(shutdown),
     );
 
     Ok(())
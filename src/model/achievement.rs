@@ -0,0 +1,186 @@
+use super::user::UserId;
+use crate::{database::get_pool, error::Error};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, Transaction};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Achievement {
+    FirstClick,
+    Points1000,
+    TopTenForADay,
+    SevenDayStreak,
+}
+
+impl Achievement {
+    pub const ALL: [Achievement; 4] = [
+        Achievement::FirstClick,
+        Achievement::Points1000,
+        Achievement::TopTenForADay,
+        Achievement::SevenDayStreak,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Achievement::FirstClick => "first_click",
+            Achievement::Points1000 => "points_1000",
+            Achievement::TopTenForADay => "top_ten_for_a_day",
+            Achievement::SevenDayStreak => "seven_day_streak",
+        }
+    }
+
+    fn parse(achievement: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|known| known.as_str() == achievement)
+    }
+
+    pub fn title(self) -> &'static str {
+        match self {
+            Achievement::FirstClick => "Erster Klick",
+            Achievement::Points1000 => "1000 Punkte",
+            Achievement::TopTenForADay => "Einen Tag lang in den Top 10",
+            Achievement::SevenDayStreak => "7 Tage in Folge gespielt",
+        }
+    }
+}
+
+pub struct Unlock {
+    pub achievement: Achievement,
+    pub unlocked: DateTime<Utc>,
+}
+
+/// The achievements of one user, in the order they were unlocked.
+pub async fn unlocked(user_id: UserId) -> Result<Vec<Unlock>, Error> {
+    Ok(sqlx::query!(
+        "SELECT achievement, unlocked
+        FROM achievements
+        WHERE user_id = $1
+        ORDER BY unlocked",
+        user_id,
+    )
+    .fetch_all(get_pool())
+    .await?
+    .into_iter()
+    .filter_map(|row| {
+        Achievement::parse(&row.achievement).map(|achievement| Unlock {
+            achievement,
+            unlocked: row.unlocked,
+        })
+    })
+    .collect())
+}
+
+/// The achievements of all users.
+pub async fn all() -> Result<Vec<(UserId, Achievement)>, Error> {
+    Ok(sqlx::query!(
+        "SELECT user_id, achievement
+        FROM achievements",
+    )
+    .fetch_all(get_pool())
+    .await?
+    .into_iter()
+    .filter_map(|row| {
+        Achievement::parse(&row.achievement).map(|achievement| (row.user_id, achievement))
+    })
+    .collect())
+}
+
+/// Stores unlocked achievements, skipping those of users that were deleted in the meantime.
+pub async fn unlock_all(
+    transaction: &mut Transaction<'_, Postgres>,
+    unlocks: &[(UserId, Achievement)],
+) -> Result<(), Error> {
+    let (user_ids, achievements): (Vec<UserId>, Vec<String>) = unlocks
+        .iter()
+        .map(|(user_id, achievement)| (*user_id, achievement.as_str().to_owned()))
+        .unzip();
+
+    sqlx::query!(
+        "INSERT INTO achievements (user_id, achievement)
+        SELECT data.user_id, data.achievement
+        FROM UNNEST($1::INTEGER[], $2::VARCHAR[]) AS data(user_id, achievement)
+        WHERE EXISTS (
+            SELECT 1
+            FROM users
+            WHERE users.user_id = data.user_id
+        )
+        ON CONFLICT DO NOTHING",
+        &user_ids,
+        &achievements,
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    Ok(())
+}
+
+/// Since when each player in the top ten has been there.
+pub async fn top_since() -> Result<Vec<(UserId, DateTime<Utc>)>, Error> {
+    Ok(sqlx::query!(
+        "SELECT user_id, since
+        FROM top_players",
+    )
+    .fetch_all(get_pool())
+    .await?
+    .into_iter()
+    .map(|row| (row.user_id, row.since))
+    .collect())
+}
+
+/// Replaces the stored top ten, skipping users that were deleted in the meantime.
+pub async fn set_top_since(
+    transaction: &mut Transaction<'_, Postgres>,
+    top_since: &[(UserId, DateTime<Utc>)],
+) -> Result<(), Error> {
+    let (user_ids, since): (Vec<UserId>, Vec<DateTime<Utc>>) = top_since.iter().copied().unzip();
+
+    sqlx::query!("DELETE FROM top_players")
+        .execute(&mut *transaction)
+        .await?;
+
+    sqlx::query!(
+        "INSERT INTO top_players (user_id, since)
+        SELECT data.user_id, data.since
+        FROM UNNEST($1::INTEGER[], $2::TIMESTAMPTZ[]) AS data(user_id, since)
+        WHERE EXISTS (
+            SELECT 1
+            FROM users
+            WHERE users.user_id = data.user_id
+        )",
+        &user_ids,
+        &since,
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    Ok(())
+}
+
+/// Records that the user played on the given day and returns on how many
+/// of the `days` days up to it they played.
+pub async fn record_play(user_id: UserId, day: NaiveDate, days: i32) -> Result<i64, Error> {
+    sqlx::query!(
+        "INSERT INTO plays (user_id, day)
+        VALUES ($1, $2)
+        ON CONFLICT DO NOTHING",
+        user_id,
+        day,
+    )
+    .execute(get_pool())
+    .await?;
+
+    Ok(sqlx::query!(
+        r#"SELECT COUNT(*) AS "count!"
+        FROM plays
+        WHERE user_id = $1
+        AND day > $2::DATE - $3::INTEGER"#,
+        user_id,
+        day,
+        days,
+    )
+    .fetch_one(get_pool())
+    .await?
+    .count)
+}
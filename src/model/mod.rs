@@ -1,2 +1,5 @@
+pub mod achievement;
+pub mod season;
 pub mod session;
+pub mod totp;
 pub mod user;
@@ -0,0 +1,139 @@
+use crate::{database::get_pool, error::Error};
+use chrono::{DateTime, Duration, Utc};
+
+pub type SeasonId = i32;
+
+pub struct Season {
+    pub season_id: SeasonId,
+    pub started: DateTime<Utc>,
+    pub ends: DateTime<Utc>,
+}
+
+pub struct Standing {
+    pub rank: i32,
+    pub username: String,
+    pub score: i32,
+}
+
+impl Season {
+    /// The season that is currently running, if any.
+    pub async fn current() -> Result<Option<Season>, Error> {
+        Ok(sqlx::query_as!(
+            Season,
+            "SELECT season_id, started, ends
+            FROM seasons
+            WHERE NOT ended
+            ORDER BY season_id DESC
+            LIMIT 1",
+        )
+        .fetch_optional(get_pool())
+        .await?)
+    }
+
+    pub async fn start(duration: Duration) -> Result<Season, Error> {
+        Ok(sqlx::query_as!(
+            Season,
+            "INSERT INTO seasons (ends)
+            VALUES ($1)
+            RETURNING season_id, started, ends",
+            Utc::now() + duration,
+        )
+        .fetch_one(get_pool())
+        .await?)
+    }
+
+    /// Archives the final standings, resets every score and starts the next season,
+    /// all in one transaction. Ending a season twice only returns the running one.
+    pub async fn end(season_id: SeasonId, duration: Duration) -> Result<Season, Error> {
+        let mut transaction = get_pool().begin().await?;
+
+        let ended = sqlx::query!(
+            "UPDATE seasons
+            SET ended = TRUE
+            WHERE season_id = $1
+            AND NOT ended",
+            season_id,
+        )
+        .execute(&mut transaction)
+        .await?
+        .rows_affected();
+
+        if ended == 0 {
+            transaction.rollback().await?;
+            return match Season::current().await? {
+                Some(season) => Ok(season),
+                None => Season::start(duration).await,
+            };
+        }
+
+        sqlx::query!(
+            "INSERT INTO standings (season_id, username, rank, score)
+            SELECT $1, username, RANK() OVER (ORDER BY score DESC), score
+            FROM states
+            NATURAL JOIN users",
+            season_id,
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        sqlx::query!(
+            "UPDATE states
+            SET score = 0",
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        let season = sqlx::query_as!(
+            Season,
+            "INSERT INTO seasons (ends)
+            VALUES ($1)
+            RETURNING season_id, started, ends",
+            Utc::now() + duration,
+        )
+        .fetch_one(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+
+        Ok(season)
+    }
+
+    /// All seasons that are over, latest first.
+    pub async fn past() -> Result<Vec<Season>, Error> {
+        Ok(sqlx::query_as!(
+            Season,
+            "SELECT season_id, started, ends
+            FROM seasons
+            WHERE ended
+            ORDER BY season_id DESC",
+        )
+        .fetch_all(get_pool())
+        .await?)
+    }
+
+    pub async fn get(season_id: SeasonId) -> Result<Option<Season>, Error> {
+        Ok(sqlx::query_as!(
+            Season,
+            "SELECT season_id, started, ends
+            FROM seasons
+            WHERE season_id = $1
+            AND ended",
+            season_id,
+        )
+        .fetch_optional(get_pool())
+        .await?)
+    }
+
+    pub async fn standings(&self) -> Result<Vec<Standing>, Error> {
+        Ok(sqlx::query_as!(
+            Standing,
+            "SELECT rank, username, score
+            FROM standings
+            WHERE season_id = $1
+            ORDER BY rank, username",
+            self.season_id,
+        )
+        .fetch_all(get_pool())
+        .await?)
+    }
+}
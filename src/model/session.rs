@@ -1,16 +1,66 @@
-use super::user::User;
-use crate::{database::get_pool, error::Error};
+use super::user::{Flash, User, UserId};
+use crate::{
+    database::get_pool,
+    env::{BEHIND_PROXY, COOKIE_SAME_SITE, COOKIE_SECURE},
+    error::Error,
+    shutdown_requested, Shutdown,
+};
 use askama::Template;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
+use once_cell::sync::Lazy;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
-use std::{fmt::Display, iter};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    iter,
+    net::{IpAddr, SocketAddr},
+};
+use tokio::time;
 use urlencoding::{decode, encode};
 use warp::{
     http, reject, {Filter, Rejection, Reply},
 };
 
 pub type Flashes = Vec<String>;
+/// Identifies a stored session without revealing its secret id.
+pub type PublicId = i32;
+
+/// How long a session lives without being used.
+const SESSION_DAYS: i64 = 7;
+/// How long after entering the password the second factor has to be provided.
+const SECOND_FACTOR_TIMEOUT_MINUTES: i64 = 5;
+/// Length of session ids and CSRF tokens.
+const ID_LENGTH: usize = 32;
+/// How much of the user agent is stored.
+const USER_AGENT_LENGTH: usize = 256;
+/// How often expired sessions are deleted.
+const CLEANUP_INTERVAL: time::Duration = time::Duration::from_secs(60 * 60);
+
+/// Browsers only accept the `__Host-` prefix on secure cookies,
+/// it keeps subdomains and plain HTTP from overwriting them.
+static SESSION_COOKIE: Lazy<&str> = Lazy::new(|| {
+    if *COOKIE_SECURE {
+        "__Host-session-id"
+    } else {
+        "session-id"
+    }
+});
+static FLASHES_COOKIE: Lazy<&str> = Lazy::new(|| {
+    if *COOKIE_SECURE {
+        "__Host-flashes"
+    } else {
+        "flashes"
+    }
+});
+/// Carries the CSRF token of sessions that aren't stored, forms have to submit the same token.
+static CSRF_COOKIE: Lazy<&str> = Lazy::new(|| {
+    if *COOKIE_SECURE {
+        "__Host-csrf-token"
+    } else {
+        "csrf-token"
+    }
+});
 
 #[derive(Template, Copy, Clone)]
 #[template(path = "layout.html")]
@@ -24,6 +74,17 @@ pub struct Session {
     flashes: Flashes,
 }
 
+/// A stored session of a user as listed on the account page.
+pub struct ActiveSession {
+    pub public_id: PublicId,
+    pub created: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    /// Whether this is the session the list was requested with.
+    pub current: bool,
+}
+
 impl Session {
     pub fn get_user_id(&self) -> Result<i32, Error> {
         self.cookie
@@ -49,6 +110,43 @@ impl Session {
         self.layout
     }
 
+    /// The token every form of this session has to send back.
+    /// Sessions that aren't stored keep it in a cookie instead.
+    pub fn get_csrf_token(&self) -> String {
+        self.cookie.csrf_token.clone()
+    }
+
+    pub fn get_public_id(&self) -> Option<PublicId> {
+        self.cookie.public_id
+    }
+
+    /// Anonymous visitors only get a stored session once they sign in.
+    async fn persist(&mut self) -> Result<(), Error> {
+        if self.cookie.public_id.is_some() {
+            return Ok(());
+        }
+
+        self.cookie.public_id = Some(
+            sqlx::query!(
+                "INSERT INTO sessions (session_id, expires, csrf_token, user_agent, ip)
+                VALUES ($1, $2, $3, $4, $5)
+                RETURNING public_id",
+                self.cookie.session_id,
+                self.cookie.expires,
+                self.cookie.csrf_token,
+                self.cookie.user_agent,
+                self.cookie.ip,
+            )
+            .fetch_one(get_pool())
+            .await?
+            .public_id,
+        );
+
+        log::info!("new session {} created", self.cookie.session_id);
+
+        Ok(())
+    }
+
     pub fn add_flashes<T, D: Display>(&mut self, result: Result<T, Vec<D>>) -> Option<T> {
         match result {
             Ok(t) => Some(t),
@@ -66,13 +164,15 @@ impl Session {
     }
 
     pub async fn link_user(&mut self, user: User) -> Result<(), Error> {
+        self.persist().await?;
+
         sqlx::query!(
             "UPDATE sessions
             SET user_id = (
                 SELECT user_id
                 FROM users
                 WHERE username = $2
-            )
+            ), pending_user_id = NULL
             WHERE session_id = $1",
             self.cookie.session_id,
             user.username,
@@ -80,23 +180,157 @@ impl Session {
         .execute(get_pool())
         .await?;
 
-        Ok(())
+        self.rotate_id().await
     }
 
     pub async fn unlink_user(&mut self) -> Result<(), Error> {
+        if self.cookie.public_id.is_none() {
+            return Ok(());
+        }
+
+        sqlx::query!(
+            "UPDATE sessions
+            SET user_id = NULL, pending_user_id = NULL
+            WHERE session_id = $1",
+            self.cookie.session_id,
+        )
+        .execute(get_pool())
+        .await?;
+        self.cookie.pending_user_id = None;
+
+        self.rotate_id().await
+    }
+
+    /// The user that entered their password but still has to provide a second factor.
+    /// Only for a few minutes, after that the password has to be entered again.
+    pub fn get_pending_user_id(&self) -> Option<UserId> {
+        self.cookie.pending_user_id
+    }
+
+    /// Puts the session into the state in between password and second factor,
+    /// in which it isn't signed in yet.
+    pub async fn require_second_factor(&mut self, user_id: UserId) -> Result<(), Error> {
+        self.persist().await?;
+
         sqlx::query!(
             "UPDATE sessions
-            SET user_id = NULL
+            SET pending_user_id = $2, pending_since = NOW()
             WHERE session_id = $1",
             self.cookie.session_id,
+            user_id,
         )
         .execute(get_pool())
         .await?;
+        self.cookie.pending_user_id = Some(user_id);
 
         Ok(())
     }
 
-    
+    /// Signs in the user that provided a valid second factor,
+    /// unless the sign-in expired while they were entering it.
+    pub async fn complete_second_factor(&mut self) -> Result<(), Error> {
+        let completed = sqlx::query!(
+            "UPDATE sessions
+            SET user_id = pending_user_id, pending_user_id = NULL
+            WHERE session_id = $1
+            AND pending_since > $2",
+            self.cookie.session_id,
+            Utc::now() - Duration::minutes(SECOND_FACTOR_TIMEOUT_MINUTES),
+        )
+        .execute(get_pool())
+        .await?
+        .rows_affected();
+
+        if completed == 0 {
+            return Err(Error::Unauthorized);
+        }
+        self.cookie.user_id = self.cookie.pending_user_id.take();
+
+        self.rotate_id().await
+    }
+
+    /// Moves the session to a new id and CSRF token whenever its privileges change,
+    /// so that an id planted or leaked before can't be used to take it over.
+    async fn rotate_id(&mut self) -> Result<(), Error> {
+        let session_id = Cookie::random_id();
+        let csrf_token = Cookie::random_id();
+
+        sqlx::query!(
+            "UPDATE sessions
+            SET session_id = $2, csrf_token = $3
+            WHERE session_id = $1",
+            self.cookie.session_id,
+            session_id,
+            csrf_token,
+        )
+        .execute(get_pool())
+        .await?;
+
+        self.cookie.session_id = session_id;
+        self.cookie.csrf_token = csrf_token;
+
+        Ok(())
+    }
+
+    /// All sessions of the signed in user that haven't expired, the most recently used first.
+    pub async fn list_sessions(&self) -> Result<Vec<ActiveSession>, Error> {
+        Ok(sqlx::query_as!(
+            ActiveSession,
+            r#"SELECT public_id, created, last_seen, user_agent, ip, session_id = $2 AS "current!"
+            FROM sessions
+            WHERE user_id = $1
+            AND expires > NOW()
+            ORDER BY last_seen DESC"#,
+            self.get_user_id()?,
+            self.cookie.session_id,
+        )
+        .fetch_all(get_pool())
+        .await?)
+    }
+
+    /// Deletes another session of the signed in user.
+    /// The current session can't be revoked this way, it has to sign out instead.
+    pub async fn revoke_session(
+        &self,
+        public_id: PublicId,
+    ) -> Result<Result<(), Vec<Flash>>, Error> {
+        let revoked = sqlx::query!(
+            "DELETE FROM sessions
+            WHERE user_id = $1
+            AND public_id = $2
+            AND session_id <> $3",
+            self.get_user_id()?,
+            public_id,
+            self.cookie.session_id,
+        )
+        .execute(get_pool())
+        .await?
+        .rows_affected();
+
+        if revoked > 0 {
+            Ok(Ok(()))
+        } else {
+            Ok(Err(vec![Flash::SessionDoesNotExist]))
+        }
+    }
+
+    /// Deletes all sessions of the signed in user except the current one and returns them.
+    pub async fn revoke_other_sessions(&self) -> Result<Vec<PublicId>, Error> {
+        Ok(sqlx::query!(
+            "DELETE FROM sessions
+            WHERE user_id = $1
+            AND session_id <> $2
+            RETURNING public_id",
+            self.get_user_id()?,
+            self.cookie.session_id,
+        )
+        .fetch_all(get_pool())
+        .await?
+        .into_iter()
+        .map(|row| row.public_id)
+        .collect())
+    }
+
     pub async fn update_username(&self, username: String) -> Result<(), Error> {
         sqlx::query!(
             "UPDATE users
@@ -111,7 +345,7 @@ impl Session {
         Ok(())
     }
 
-    pub async fn update_password(&self, password: String) -> Result<(), Error> {
+    pub async fn update_password(&mut self, password: String) -> Result<(), Error> {
         sqlx::query!(
             "UPDATE users
             SET password = $1
@@ -122,7 +356,7 @@ impl Session {
         .execute(get_pool())
         .await?;
 
-        Ok(())
+        self.rotate_id().await
     }
 }
 
@@ -130,6 +364,12 @@ struct Cookie {
     session_id: String,
     user_id: Option<i32>,
     expires: DateTime<Utc>,
+    csrf_token: String,
+    /// `None` until the session is stored.
+    public_id: Option<PublicId>,
+    user_agent: Option<String>,
+    ip: Option<String>,
+    pending_user_id: Option<UserId>,
 }
 
 impl Cookie {
@@ -137,107 +377,215 @@ impl Cookie {
         let mut rng = thread_rng();
         iter::repeat(())
             .map(|_| rng.sample(Alphanumeric))
-            .take(32)
+            .take(ID_LENGTH)
             .map(|x| x as char)
             .collect()
     }
 
-    pub async fn from_id(id: Option<String>) -> Result<Cookie, Error> {
-        let cookie = if let Some(id) = id {
-            sqlx::query_as!(
-                Cookie,
-                "SELECT *
-                FROM sessions
-                WHERE session_id = $1
-                AND expires > NOW()",
-                id
-            )
-            .fetch_all(get_pool())
-            .await?
-            .pop()
-        } else {
-            None
-        };
+    fn is_id(id: &str) -> bool {
+        id.len() == ID_LENGTH && id.chars().all(|c| c.is_ascii_alphanumeric())
+    }
 
-        let cookie = if let Some(cookie) = cookie {
-            log::info!("session {} connected", cookie.session_id);
+    /// A session that isn't stored, it keeps the CSRF token of its cookie if there is one.
+    fn new(user_agent: Option<String>, ip: Option<String>, csrf_token: Option<String>) -> Cookie {
+        Cookie {
+            session_id: Cookie::random_id(),
+            user_id: None,
+            expires: Utc::now() + Duration::days(SESSION_DAYS),
+            csrf_token: csrf_token
+                .filter(|csrf_token| Cookie::is_id(csrf_token))
+                .unwrap_or_else(Cookie::random_id),
+            public_id: None,
+            user_agent,
+            ip,
+            pending_user_id: None,
+        }
+    }
 
-            cookie
-        } else {
-            let cookie = sqlx::query_as!(
-                Cookie,
-                "INSERT INTO sessions (session_id)
-                VALUES ($1)
-                RETURNING *",
-                Cookie::random_id(),
-            )
-            .fetch_one(get_pool())
-            .await?;
+    /// Looks up a stored session and extends its expiry, since it is still in use.
+    /// The device it is used from is updated along with it.
+    async fn from_id(
+        id: Option<String>,
+        user_agent: &Option<String>,
+        ip: &Option<String>,
+    ) -> Result<Option<Cookie>, Error> {
+        let id = match id {
+            Some(id) => id,
+            None => return Ok(None),
+        };
 
-            log::info!("new session {} created", cookie.session_id);
+        let cookie = sqlx::query_as!(
+            Cookie,
+            r#"UPDATE sessions
+            SET expires = $2, last_seen = NOW(), user_agent = $3, ip = $4
+            WHERE session_id = $1
+            AND expires > NOW()
+            RETURNING session_id, user_id, expires, csrf_token,
+            public_id AS "public_id?", user_agent, ip,
+            CASE WHEN pending_since > $5 THEN pending_user_id END AS "pending_user_id?""#,
+            id,
+            Utc::now() + Duration::days(SESSION_DAYS),
+            user_agent.as_ref(),
+            ip.as_ref(),
+            Utc::now() - Duration::minutes(SECOND_FACTOR_TIMEOUT_MINUTES),
+        )
+        .fetch_optional(get_pool())
+        .await?;
 
-            cookie
-        };
+        if let Some(cookie) = &cookie {
+            log::info!("session {} connected", cookie.session_id);
+        }
 
         Ok(cookie)
     }
 }
 
+/// Extracts the user agent and IP address the request was made from.
+/// Behind a proxy, the address is the last one it added to `X-Forwarded-For`.
+/// Anything that isn't a valid address is left out.
+fn with_device(
+) -> impl Filter<Extract = (Option<String>, Option<String>), Error = Rejection> + Clone {
+    warp::any()
+        .and(warp::header::optional::<String>("user-agent"))
+        .and(warp::addr::remote())
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .map(
+            |user_agent: Option<String>,
+             remote: Option<SocketAddr>,
+             forwarded_for: Option<String>| {
+                let ip: Option<IpAddr> = if *BEHIND_PROXY {
+                    forwarded_for.and_then(|forwarded_for| {
+                        forwarded_for
+                            .rsplit(',')
+                            .next()
+                            .and_then(|ip| ip.trim().parse().ok())
+                    })
+                } else {
+                    remote.map(|remote| remote.ip())
+                };
+
+                (
+                    user_agent
+                        .map(|user_agent| user_agent.chars().take(USER_AGENT_LENGTH).collect()),
+                    ip.map(|ip| ip.to_string()),
+                )
+            },
+        )
+        .untuple_one()
+}
+
 pub fn with_session() -> impl Filter<Extract = (Session,), Error = Rejection> + Clone {
     warp::any()
-        .and(warp::cookie::optional::<String>("session-id"))
-        .and(warp::cookie::optional::<String>("flashes"))
-        .and_then(|id: Option<String>, flashes: Option<String>| async move {
-            match Cookie::from_id(id).await {
-                Ok(cookie) => Ok((Session {
-                    layout: Layout {
-                        signed_in: cookie.user_id.is_some(),
-                    },
-                    cookie,
-                    flashes: flashes
-                        // TODO: Proper error handling.
-                        .map(|string| {
-                            decode(&string)
-                                .unwrap_or(String::new())
-                                .split('|')
-                                .map(|flash| flash.to_owned())
-                                .collect()
-                        })
-                        .unwrap_or(Vec::new()),
-                },)),
-                Err(err) => Err(reject::custom(err)),
-            }
-        })
+        .and(warp::cookie::optional::<String>(*SESSION_COOKIE))
+        .and(warp::cookie::optional::<String>(*FLASHES_COOKIE))
+        .and(warp::cookie::optional::<String>(*CSRF_COOKIE))
+        .and(with_device())
+        .and_then(
+            |id: Option<String>,
+             flashes: Option<String>,
+             csrf_token: Option<String>,
+             user_agent: Option<String>,
+             ip: Option<String>| async move {
+                match Cookie::from_id(id, &user_agent, &ip).await {
+                    Ok(cookie) => Ok((Session {
+                        layout: Layout {
+                            signed_in: cookie
+                                .as_ref()
+                                .map_or(false, |cookie| cookie.user_id.is_some()),
+                        },
+                        cookie: cookie.unwrap_or_else(|| Cookie::new(user_agent, ip, csrf_token)),
+                        flashes: flashes
+                            // TODO: Proper error handling.
+                            .map(|string| {
+                                decode(&string)
+                                    .unwrap_or(String::new())
+                                    .split('|')
+                                    .map(|flash| flash.to_owned())
+                                    .collect()
+                            })
+                            .unwrap_or(Vec::new()),
+                    },)),
+                    Err(err) => Err(reject::custom(err)),
+                }
+            },
+        )
+        .untuple_one()
+}
+
+/// Compares in constant time so the token can't be guessed one character at a time.
+fn tokens_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+/// Extracts the session together with a submitted form,
+/// rejecting forms that don't carry the session's CSRF token.
+pub fn with_session_form(
+) -> impl Filter<Extract = (Session, HashMap<String, String>), Error = Rejection> + Clone {
+    with_session()
+        .and(warp::body::form())
+        .and_then(
+            |session: Session, mut form: HashMap<String, String>| async move {
+                match form.remove("csrf-token") {
+                    Some(token) if tokens_match(&token, &session.cookie.csrf_token) => {
+                        Ok((session, form))
+                    }
+                    _ => Err(reject::custom(Error::Forbidden)),
+                }
+            },
+        )
         .untuple_one()
 }
 
+/// Formats a cookie with the attributes configured through the environment.
+fn set_cookie(name: &str, value: &str, max_age: u64) -> String {
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite={}{}",
+        name,
+        value,
+        max_age,
+        *COOKIE_SAME_SITE,
+        if *COOKIE_SECURE { "; Secure" } else { "" },
+    )
+}
+
 pub async fn update_session(
     reply: impl Reply,
     mut session: Session,
 ) -> Result<impl warp::Reply, std::convert::Infallible> {
-    let reply = warp::reply::with_header(
-        reply,
-        http::header::SET_COOKIE,
-        format!(
-            "session-id={}; Path=/; Max-Age={}; HttpOnly",
-            session.cookie.session_id,
-            session
-                .cookie
-                .expires
-                .signed_duration_since(Utc::now())
-                .to_std()
-                .unwrap()
-                .as_secs(),
-        ),
-    );
+    let max_age = session
+        .cookie
+        .expires
+        .signed_duration_since(Utc::now())
+        .to_std()
+        .unwrap()
+        .as_secs();
+    // Sessions that were never stored only get a cookie with their CSRF token,
+    // stale cookies are removed.
+    let (session_cookie, csrf_cookie) = if session.cookie.public_id.is_some() {
+        (
+            set_cookie(*SESSION_COOKIE, &session.cookie.session_id, max_age),
+            set_cookie(*CSRF_COOKIE, "", 0),
+        )
+    } else {
+        (
+            set_cookie(*SESSION_COOKIE, "", 0),
+            set_cookie(*CSRF_COOKIE, &session.cookie.csrf_token, max_age),
+        )
+    };
+    let reply = warp::reply::with_header(reply, http::header::SET_COOKIE, session_cookie);
+    let reply = warp::reply::with_header(reply, http::header::SET_COOKIE, csrf_cookie);
 
     let reply = warp::reply::with_header(
         reply,
         http::header::SET_COOKIE,
-        format!(
-            "flashes={}; Path=/; Max-Age={}; HttpOnly",
-            encode(&session.flashes.join("|")),
-            if session.flashes.is_empty() { 0 } else { 60 }
+        set_cookie(
+            *FLASHES_COOKIE,
+            &encode(&session.flashes.join("|")),
+            if session.flashes.is_empty() { 0 } else { 60 },
         ),
     );
 
@@ -245,3 +593,28 @@ pub async fn update_session(
 
     Ok(reply)
 }
+
+/// Deletes expired sessions from time to time, they can't be used anymore anyway.
+/// Returns once the server is asked to stop.
+pub async fn clean_up(shutdown: Shutdown) {
+    let mut cleanups = time::interval(CLEANUP_INTERVAL);
+    let stopped = shutdown_requested(shutdown);
+    tokio::pin!(stopped);
+    loop {
+        tokio::select! {
+            _ = cleanups.tick() => {},
+            _ = &mut stopped => break,
+        }
+
+        match sqlx::query!(
+            "DELETE FROM sessions
+            WHERE expires <= NOW()",
+        )
+        .execute(get_pool())
+        .await
+        {
+            Ok(result) => log::info!("deleted {} expired sessions", result.rows_affected()),
+            Err(err) => log::error!("failed to delete expired sessions: {}", err),
+        }
+    }
+}
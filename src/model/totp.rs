@@ -0,0 +1,443 @@
+use super::user::{Flash, UserId};
+use crate::{database::get_pool, env::TOTP_ISSUER, error::Error};
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac, NewMac};
+use qrcode::{render::svg, QrCode};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::iter;
+use urlencoding::encode;
+
+/// How many seconds a code is valid for.
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+/// How many steps a code may be off, to allow for clocks that are slightly off.
+const SKEW: i64 = 1;
+/// Length of a secret in bytes, as recommended for HMAC-SHA1.
+const SECRET_LENGTH: usize = 20;
+const RECOVERY_CODES: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+/// How many wrong codes in a row lock a user's second factor.
+const MAX_FAILURES: i32 = 5;
+/// How long a locked second factor rejects every code.
+const LOCKOUT_MINUTES: i64 = 15;
+
+/// Whether a user has set up two-factor authentication.
+pub enum State {
+    Disabled,
+    /// A secret was generated but not yet confirmed with a code.
+    Pending(Vec<u8>),
+    Enabled,
+}
+
+/// What an authenticator app needs to be set up.
+pub struct Setup {
+    pub uri: String,
+    /// The URI as an SVG image.
+    pub qr_code: String,
+}
+
+impl Setup {
+    pub fn new(secret: &[u8], username: &str) -> Self {
+        let uri = format!(
+            "otpauth://totp/{issuer}:{username}?secret={secret}&issuer={issuer}&digits={digits}&period={period}",
+            issuer = encode(&TOTP_ISSUER),
+            username = encode(username),
+            secret = base32(secret),
+            digits = DIGITS,
+            period = STEP_SECONDS,
+        );
+        let qr_code = QrCode::new(uri.as_bytes())
+            .unwrap()
+            .render::<svg::Color>()
+            .min_dimensions(200, 200)
+            .build();
+
+        Self { uri, qr_code }
+    }
+}
+
+/// Encodes the secret the way authenticator apps expect it, RFC 4648 base32 without padding.
+fn base32(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+    let mut encoded = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+
+    encoded
+}
+
+/// The HOTP value of RFC 4226 for the given counter.
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_varkey(secret).unwrap();
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset],
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]) & 0x7fff_ffff;
+
+    binary % 10u32.pow(DIGITS)
+}
+
+/// Returns the time step the code is valid for, if it is valid at the given Unix time
+/// and newer than the last one used, so that a code can't be used twice.
+fn verify(secret: &[u8], code: &str, last_step: Option<i64>, time: i64) -> Option<i64> {
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+    let code = code.parse::<u32>().ok()?;
+
+    let now = time / STEP_SECONDS;
+    (now - SKEW..=now + SKEW)
+        .filter(|step| last_step.map_or(true, |last_step| *step > last_step))
+        .find(|step| hotp(secret, *step as u64) == code)
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    format!("{:x}", Sha256::digest(code.trim().as_bytes()))
+}
+
+/// The user with this name if they have to provide a second factor to sign in.
+pub async fn required_for(username: &str) -> Result<Option<UserId>, Error> {
+    Ok(sqlx::query!(
+        "SELECT user_id
+        FROM users
+        WHERE username = $1
+        AND totp_secret IS NOT NULL",
+        username,
+    )
+    .fetch_optional(get_pool())
+    .await?
+    .map(|row| row.user_id))
+}
+
+pub async fn state(user_id: UserId) -> Result<State, Error> {
+    let row = sqlx::query!(
+        "SELECT totp_secret, totp_pending
+        FROM users
+        WHERE user_id = $1",
+        user_id,
+    )
+    .fetch_one(get_pool())
+    .await?;
+
+    Ok(match (row.totp_secret, row.totp_pending) {
+        (Some(_), _) => State::Enabled,
+        (None, Some(pending)) => State::Pending(pending),
+        (None, None) => State::Disabled,
+    })
+}
+
+/// Generates a new secret, which only takes effect once it is confirmed.
+pub async fn begin_setup(user_id: UserId) -> Result<(), Error> {
+    let secret: Vec<u8> = {
+        let mut rng = thread_rng();
+        iter::repeat_with(|| rng.gen())
+            .take(SECRET_LENGTH)
+            .collect()
+    };
+
+    sqlx::query!(
+        "UPDATE users
+        SET totp_pending = $2
+        WHERE user_id = $1
+        AND totp_secret IS NULL",
+        user_id,
+        secret,
+    )
+    .execute(get_pool())
+    .await?;
+
+    Ok(())
+}
+
+/// Enables two-factor authentication if the code matches the pending secret
+/// and returns new recovery codes, which are only stored hashed.
+pub async fn confirm_setup(
+    user_id: UserId,
+    code: &str,
+) -> Result<Result<Vec<String>, Vec<Flash>>, Error> {
+    let pending = match state(user_id).await? {
+        State::Pending(pending) => pending,
+        _ => return Ok(Err(vec![Flash::InvalidCode])),
+    };
+    let step = match verify(&pending, code, None, Utc::now().timestamp()) {
+        Some(step) => step,
+        None => return Ok(Err(vec![Flash::InvalidCode])),
+    };
+
+    let codes: Vec<String> = (0..RECOVERY_CODES)
+        .map(|_| {
+            let mut rng = thread_rng();
+            iter::repeat(())
+                .map(|_| rng.sample(Alphanumeric))
+                .take(RECOVERY_CODE_LENGTH)
+                .map(|x| x as char)
+                .collect()
+        })
+        .collect();
+    let hashes: Vec<String> = codes.iter().map(|code| hash_recovery_code(code)).collect();
+
+    let mut transaction = get_pool().begin().await?;
+
+    sqlx::query!(
+        "UPDATE users
+        SET totp_secret = totp_pending, totp_pending = NULL, totp_last_step = $2
+        WHERE user_id = $1",
+        user_id,
+        step,
+    )
+    .execute(&mut transaction)
+    .await?;
+
+    sqlx::query!(
+        "DELETE FROM recovery_codes
+        WHERE user_id = $1",
+        user_id,
+    )
+    .execute(&mut transaction)
+    .await?;
+
+    sqlx::query!(
+        "INSERT INTO recovery_codes (user_id, code_hash)
+        SELECT $1, UNNEST($2::VARCHAR[])",
+        user_id,
+        &hashes,
+    )
+    .execute(&mut transaction)
+    .await?;
+
+    transaction.commit().await?;
+
+    Ok(Ok(codes))
+}
+
+/// Checks a code from the authenticator app or, failing that, a recovery code,
+/// which is used up by this. Too many wrong codes in a row lock the second factor for a while,
+/// no matter which session they were entered in, so codes can't be guessed.
+pub async fn check(user_id: UserId, code: &str) -> Result<Result<(), Vec<Flash>>, Error> {
+    let row = sqlx::query!(
+        "SELECT totp_secret, totp_last_step, second_factor_locked_until
+        FROM users
+        WHERE user_id = $1",
+        user_id,
+    )
+    .fetch_one(get_pool())
+    .await?;
+
+    if row
+        .second_factor_locked_until
+        .map_or(false, |locked_until| locked_until > Utc::now())
+    {
+        return Ok(Err(vec![Flash::TooManyAttempts]));
+    }
+    let secret = match row.totp_secret {
+        Some(secret) => secret,
+        None => return Ok(Err(vec![Flash::InvalidCode])),
+    };
+
+    if accept(user_id, &secret, row.totp_last_step, code).await? {
+        sqlx::query!(
+            "UPDATE users
+            SET second_factor_failures = 0
+            WHERE user_id = $1",
+            user_id,
+        )
+        .execute(get_pool())
+        .await?;
+
+        return Ok(Ok(()));
+    }
+
+    let locked_until = sqlx::query!(
+        "UPDATE users
+        SET second_factor_failures = CASE
+            WHEN second_factor_failures + 1 >= $2 THEN 0
+            ELSE second_factor_failures + 1
+        END, second_factor_locked_until = CASE
+            WHEN second_factor_failures + 1 >= $2 THEN $3
+            ELSE second_factor_locked_until
+        END
+        WHERE user_id = $1
+        RETURNING second_factor_locked_until",
+        user_id,
+        MAX_FAILURES,
+        Utc::now() + Duration::minutes(LOCKOUT_MINUTES),
+    )
+    .fetch_one(get_pool())
+    .await?
+    .second_factor_locked_until;
+
+    if locked_until.map_or(false, |locked_until| locked_until > Utc::now()) {
+        Ok(Err(vec![Flash::TooManyAttempts]))
+    } else {
+        Ok(Err(vec![Flash::InvalidCode]))
+    }
+}
+
+/// Uses up the code if it is valid.
+async fn accept(
+    user_id: UserId,
+    secret: &[u8],
+    last_step: Option<i64>,
+    code: &str,
+) -> Result<bool, Error> {
+    if let Some(step) = verify(secret, code, last_step, Utc::now().timestamp()) {
+        // Only one of two concurrent requests with the same code gets through.
+        let used = sqlx::query!(
+            "UPDATE users
+            SET totp_last_step = $2
+            WHERE user_id = $1
+            AND (totp_last_step IS NULL OR totp_last_step < $2)",
+            user_id,
+            step,
+        )
+        .execute(get_pool())
+        .await?
+        .rows_affected();
+
+        if used > 0 {
+            return Ok(true);
+        }
+    }
+
+    let recovered = sqlx::query!(
+        "DELETE FROM recovery_codes
+        WHERE user_id = $1
+        AND code_hash = $2",
+        user_id,
+        hash_recovery_code(code),
+    )
+    .execute(get_pool())
+    .await?
+    .rows_affected();
+
+    Ok(recovered > 0)
+}
+
+/// Turns two-factor authentication off, which needs a valid code as well.
+pub async fn disable(user_id: UserId, code: &str) -> Result<Result<(), Vec<Flash>>, Error> {
+    if let Err(flashes) = check(user_id, code).await? {
+        return Ok(Err(flashes));
+    }
+
+    let mut transaction = get_pool().begin().await?;
+
+    sqlx::query!(
+        "UPDATE users
+        SET totp_secret = NULL, totp_pending = NULL, totp_last_step = NULL
+        WHERE user_id = $1",
+        user_id,
+    )
+    .execute(&mut transaction)
+    .await?;
+
+    sqlx::query!(
+        "DELETE FROM recovery_codes
+        WHERE user_id = $1",
+        user_id,
+    )
+    .execute(&mut transaction)
+    .await?;
+
+    transaction.commit().await?;
+
+    Ok(Ok(()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The secret of the test vectors in RFC 4226 and RFC 6238.
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn hotp_matches_rfc_4226() {
+        let expected = [
+            755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+        ];
+        for (counter, code) in expected.iter().enumerate() {
+            assert_eq!(hotp(SECRET, counter as u64), *code, "counter {}", counter);
+        }
+    }
+
+    #[test]
+    fn verify_matches_rfc_6238() {
+        // The SHA-1 vectors, truncated to six digits.
+        let expected = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ];
+        for (time, code) in expected.iter() {
+            assert_eq!(
+                verify(SECRET, code, None, *time),
+                Some(time / STEP_SECONDS),
+                "time {}",
+                time
+            );
+        }
+    }
+
+    #[test]
+    fn verify_rejects_used_steps() {
+        let time = 1234567890;
+        let step = time / STEP_SECONDS;
+        let code = format!("{:06}", hotp(SECRET, step as u64));
+
+        assert_eq!(verify(SECRET, &code, Some(step - 1), time), Some(step));
+        assert_eq!(verify(SECRET, &code, Some(step), time), None);
+        assert_eq!(verify(SECRET, &code, Some(step + 1), time), None);
+    }
+
+    #[test]
+    fn verify_rejects_wrong_codes() {
+        let time = 1234567890;
+        let step = time / STEP_SECONDS;
+        let code = format!("{:06}", hotp(SECRET, step as u64));
+
+        assert_eq!(verify(SECRET, &code, None, time + 10 * STEP_SECONDS), None);
+        assert_eq!(verify(SECRET, "12345", None, time), None);
+        assert_eq!(verify(SECRET, "abcdef", None, time), None);
+    }
+
+    #[test]
+    fn base32_matches_rfc_4648() {
+        let expected = [
+            ("", ""),
+            ("f", "MY"),
+            ("fo", "MZXQ"),
+            ("foo", "MZXW6"),
+            ("foob", "MZXW6YQ"),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI"),
+        ];
+        for (bytes, encoded) in expected.iter() {
+            assert_eq!(base32(bytes.as_bytes()), *encoded);
+        }
+    }
+}
@@ -33,6 +33,9 @@ pub enum Flash {
     UsernameInvalidLength,
     PasswordInvalidLength,
     PasswordsDiffer,
+    SessionDoesNotExist,
+    InvalidCode,
+    TooManyAttempts,
 }
 
 impl fmt::Display for Flash {
@@ -50,6 +53,9 @@ impl fmt::Display for Flash {
                 Self::PasswordInvalidLength =>
                     "Das Passwort muss zwischen 4 und 32 Zeichen lang sein.",
                 Self::PasswordsDiffer => "Die Passwörter stimmen nicht überein.",
+                Self::SessionDoesNotExist => "Diese Sitzung existiert nicht mehr.",
+                Self::InvalidCode => "Der Code ist nicht korrekt.",
+                Self::TooManyAttempts => "Zu viele Fehlversuche, bitte versuche es später erneut.",
             }
         )
     }
@@ -1,9 +1,15 @@
 use crate::{
     combine,
+    game::message::CLIENT_CREATOR,
     model::{
-        session::{update_session, with_session, Flashes, Layout, Session},
+        achievement::{self, Unlock},
+        session::{
+            update_session, with_session, with_session_form, ActiveSession, Flashes, Layout,
+            PublicId, Session,
+        },
+        totp::{self, Setup, State},
         user::{
-            extract_confirm_password, extract_password, extract_username, User,
+            extract_confirm_password, extract_password, extract_username, Flash, User, UserId,
         },
     },
     Error,
@@ -18,14 +24,40 @@ struct Account {
     _parent: Layout,
     flashes: Flashes,
     username: String,
+    achievements: Vec<Unlock>,
+    sessions: Vec<ActiveSession>,
+    second_factor_enabled: bool,
+    /// Shown while the second factor is set up but not yet confirmed.
+    second_factor_setup: Option<Setup>,
+    csrf_token: String,
+}
+
+#[derive(Template)]
+#[template(path = "recovery_codes.html")]
+struct RecoveryCodes {
+    _parent: Layout,
+    codes: Vec<String>,
 }
 
 async fn get_account(mut session: Session) -> Result<(impl Reply, Session), Rejection> {
+    let username = session.get_user().await?.username;
+    let (second_factor_enabled, second_factor_setup) =
+        match totp::state(session.get_user_id()?).await? {
+            State::Enabled => (true, None),
+            State::Pending(secret) => (false, Some(Setup::new(&secret, &username))),
+            State::Disabled => (false, None),
+        };
+
     let reply = warp::reply::html(
         Account {
             _parent: session.get_layout(),
             flashes: session.get_flashes(),
-            username: session.get_user().await?.username,
+            username,
+            achievements: achievement::unlocked(session.get_user_id()?).await?,
+            sessions: session.list_sessions().await?,
+            second_factor_enabled,
+            second_factor_setup,
+            csrf_token: session.get_csrf_token(),
         }
         .render()
         .map_err(|err| Error::from(err))?,
@@ -34,7 +66,14 @@ async fn get_account(mut session: Session) -> Result<(impl Reply, Session), Reje
     Ok((reply, session))
 }
 
-async fn post_signout(mut session: Session) -> Result<(impl Reply, Session), Rejection> {
+async fn post_signout(
+    mut session: Session,
+    _form: HashMap<String, String>,
+) -> Result<(impl Reply, Session), Rejection> {
+    // Anonymous sessions have no game connections to close.
+    if let (Ok(user_id), Some(public_id)) = (session.get_user_id(), session.get_public_id()) {
+        close_sessions(user_id, &[public_id]);
+    }
     session.unlink_user().await?;
     Ok((warp::redirect(Uri::from_static("/")), session))
 }
@@ -61,6 +100,87 @@ async fn post_password(
     Ok((warp::redirect(Uri::from_static("/account")), session))
 }
 
+/// Closes the game connections of revoked or signed out sessions, they would keep playing.
+fn close_sessions(user_id: UserId, revoked: &[PublicId]) {
+    CLIENT_CREATOR
+        .get()
+        .unwrap()
+        .registry()
+        .close_sessions(user_id, revoked);
+}
+
+async fn post_revoke(
+    mut session: Session,
+    mut form: HashMap<String, String>,
+) -> Result<(impl Reply, Session), Rejection> {
+    let revoked = match form
+        .remove("session")
+        .and_then(|public_id| public_id.parse::<PublicId>().ok())
+    {
+        Some(public_id) => session.revoke_session(public_id).await?.map(|()| public_id),
+        None => Err(vec![Flash::SessionDoesNotExist]),
+    };
+
+    if let Some(public_id) = session.add_flashes(revoked) {
+        close_sessions(session.get_user_id()?, &[public_id]);
+    }
+
+    Ok((warp::redirect(Uri::from_static("/account")), session))
+}
+
+async fn post_revoke_others(
+    session: Session,
+    _form: HashMap<String, String>,
+) -> Result<(impl Reply, Session), Rejection> {
+    let revoked = session.revoke_other_sessions().await?;
+    close_sessions(session.get_user_id()?, &revoked);
+
+    Ok((warp::redirect(Uri::from_static("/account")), session))
+}
+
+async fn post_second_factor_setup(
+    session: Session,
+    _form: HashMap<String, String>,
+) -> Result<(impl Reply, Session), Rejection> {
+    totp::begin_setup(session.get_user_id()?).await?;
+
+    Ok((warp::redirect(Uri::from_static("/account")), session))
+}
+
+async fn post_second_factor_confirm(
+    mut session: Session,
+    mut form: HashMap<String, String>,
+) -> Result<(impl Reply, Session), Rejection> {
+    let code = form.remove("code").unwrap_or_default();
+    let codes = totp::confirm_setup(session.get_user_id()?, &code).await?;
+
+    // Only hashes of the recovery codes are stored, this is the only time they can be shown.
+    let reply: Box<dyn Reply> = match session.add_flashes(codes) {
+        Some(codes) => Box::new(warp::reply::html(
+            RecoveryCodes {
+                _parent: session.get_layout(),
+                codes,
+            }
+            .render()
+            .map_err(|err| Error::from(err))?,
+        )),
+        None => Box::new(warp::redirect(Uri::from_static("/account"))),
+    };
+
+    Ok((reply, session))
+}
+
+async fn post_second_factor_disable(
+    mut session: Session,
+    mut form: HashMap<String, String>,
+) -> Result<(impl Reply, Session), Rejection> {
+    let code = form.remove("code").unwrap_or_default();
+    let disabled = totp::disable(session.get_user_id()?, &code).await?;
+    session.add_flashes(disabled);
+
+    Ok((warp::redirect(Uri::from_static("/account")), session))
+}
+
 async fn post_delete(
     mut session: Session,
     mut form: HashMap<String, String>,
@@ -70,6 +190,14 @@ async fn post_delete(
         password: extract_password(&mut form),
     })) {
         if let Some(user) = session.add_flashes(user.signin().await?) {
+            // Deleting the user signs out all of their sessions but leaves their connections open.
+            let sessions: Vec<PublicId> = session
+                .list_sessions()
+                .await?
+                .into_iter()
+                .map(|active| active.public_id)
+                .collect();
+            close_sessions(session.get_user_id()?, &sessions);
             session.unlink_user().await?;
             user.delete().await?;
             return Ok((warp::redirect(Uri::from_static("/")), session));
@@ -91,24 +219,61 @@ pub fn serve() -> BoxedFilter<(impl Reply,)> {
                 .or(warp::path("username")
                     .and(warp::path::end())
                     .and(warp::post())
-                    .and(with_session())
-                    .and(warp::body::form())
+                    .and(with_session_form())
                     .and_then(post_username)
                     .untuple_one()
                     .and_then(update_session))
                 .or(warp::path("password")
                     .and(warp::path::end())
                     .and(warp::post())
-                    .and(with_session())
-                    .and(warp::body::form())
+                    .and(with_session_form())
                     .and_then(post_password)
                     .untuple_one()
                     .and_then(update_session))
+                .or(warp::path("sessions")
+                    .and(warp::path("revoke"))
+                    .and(warp::path::end())
+                    .and(warp::post())
+                    .and(with_session_form())
+                    .and_then(post_revoke)
+                    .untuple_one()
+                    .and_then(update_session))
+                .or(warp::path("sessions")
+                    .and(warp::path("revoke-others"))
+                    .and(warp::path::end())
+                    .and(warp::post())
+                    .and(with_session_form())
+                    .and_then(post_revoke_others)
+                    .untuple_one()
+                    .and_then(update_session))
+                .or(warp::path("second-factor")
+                    .and(warp::path("setup"))
+                    .and(warp::path::end())
+                    .and(warp::post())
+                    .and(with_session_form())
+                    .and_then(post_second_factor_setup)
+                    .untuple_one()
+                    .and_then(update_session))
+                .or(warp::path("second-factor")
+                    .and(warp::path("confirm"))
+                    .and(warp::path::end())
+                    .and(warp::post())
+                    .and(with_session_form())
+                    .and_then(post_second_factor_confirm)
+                    .untuple_one()
+                    .and_then(update_session))
+                .or(warp::path("second-factor")
+                    .and(warp::path("disable"))
+                    .and(warp::path::end())
+                    .and(warp::post())
+                    .and(with_session_form())
+                    .and_then(post_second_factor_disable)
+                    .untuple_one()
+                    .and_then(update_session))
                 .or(warp::path("delete")
                     .and(warp::path::end())
                     .and(warp::post())
-                    .and(with_session())
-                    .and(warp::body::form())
+                    .and(with_session_form())
                     .and_then(post_delete)
                     .untuple_one()
                     .and_then(update_session)),
@@ -116,7 +281,7 @@ pub fn serve() -> BoxedFilter<(impl Reply,)> {
         .or(warp::path("signout")
             .and(warp::path::end())
             .and(warp::post())
-            .and(with_session())
+            .and(with_session_form())
             .and_then(post_signout)
             .untuple_one()
             .and_then(update_session))
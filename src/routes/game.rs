@@ -1,13 +1,22 @@
+use crate::model::{session::PublicId, user::UserId};
 use crate::{
+    game::{
+        message::{Origin, Request, CLIENT_CREATOR},
+        protocol::{self, Encoding},
+    },
     model::session::{update_session, with_session, Layout, Session},
     Error,
 };
 use askama::Template;
+use futures::{SinkExt, StreamExt};
+use log::*;
+use std::time::Duration;
 use warp::ws::{Message, WebSocket};
 use warp::{filters::BoxedFilter, Filter, Rejection, Reply};
-use futures::{StreamExt, SinkExt};
-use log::*;
-use crate::model::user::UserId;
+
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+/// Closes connections that sent nothing, not even a pong, for this long.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
 
 #[derive(Template)]
 #[template(path = "game.html")]
@@ -30,47 +39,115 @@ async fn get_game(session: Session) -> Result<(impl Reply, Session), Rejection>
 async fn upgrade_ws(
     session: Session,
     ws: warp::ws::Ws,
+    protocols: Option<String>,
 ) -> Result<(impl Reply, Session), Rejection> {
     let user_id = session.get_user_id()?;
-    
-    Ok((
-        ws.on_upgrade(move |socket| connect_ws(user_id, socket)),
-        session,
-    ))
+    // Signed in sessions are always stored.
+    let public_id = session.get_public_id().ok_or(Error::Unauthorized)?;
+    let negotiated = protocols.as_deref().and_then(Encoding::negotiate);
+    let encoding = negotiated.unwrap_or(Encoding::Json);
+
+    let reply = ws.on_upgrade(move |socket| connect_ws(user_id, public_id, encoding, socket));
+    let reply: Box<dyn Reply> = match negotiated {
+        Some(encoding) => Box::new(warp::reply::with_header(
+            reply,
+            "sec-websocket-protocol",
+            encoding.subprotocol(),
+        )),
+        None => Box::new(reply),
+    };
+
+    Ok((reply, session))
 }
 
 // TODO: Proper error handling.
-async fn connect_ws(user_id: UserId, websocket: WebSocket) {
+async fn connect_ws(
+    user_id: UserId,
+    public_id: PublicId,
+    encoding: Encoding,
+    websocket: WebSocket,
+) {
     info!("new websocket connected");
 
     let (mut ws_tx, mut ws_rx) = websocket.split();
-    let (tx, mut rx) = crate::game::message::CLIENT_CREATOR.get().unwrap().create();
+    let client_creator = CLIENT_CREATOR.get().unwrap();
+    let registry = client_creator.registry();
+    let (tx, mut connection) = client_creator.create(user_id, public_id);
+    let origin = connection.origin(None);
 
-    tokio::join!(
-        // Send to client.
-        async move {
-            while let Some(Ok(message)) = ws_rx.next().await {
-                if message.is_text() {
-                    if let Ok(message) = String::from_utf8(message.into_bytes()) {
-                        if let Ok(message) = serde_json::from_str(&message) {
-                            tx.send((user_id, message)).await.ok();
+    // Whichever half finishes first tears down the other one.
+    tokio::select! {
+        // Receive from client.
+        _ = async {
+            loop {
+                let message = match tokio::time::timeout(IDLE_TIMEOUT, ws_rx.next()).await {
+                    Ok(Some(Ok(message))) => message,
+                    Ok(_) => break,
+                    Err(_) => {
+                        info!("websocket of user {} timed out", user_id);
+                        break;
+                    }
+                };
+
+                if message.is_text() || message.is_binary() {
+                    match protocol::decode_request(message.as_bytes(), encoding) {
+                        Ok(envelope) => {
+                            let request = Request {
+                                origin: Origin {
+                                    id: envelope.id,
+                                    ..origin
+                                },
+                                message: envelope.message,
+                            };
+                            // The game is gone, the client reconnects to reach the restarted one.
+                            if tx.send(request).await.is_err() {
+                                break;
+                            }
+                        }
+                        Err(err) => {
+                            debug!("rejected message from user {}: {}", user_id, err.message);
+                            registry.reply(
+                                Origin {
+                                    id: err.id,
+                                    ..origin
+                                },
+                                err.into_message(),
+                            );
                         }
                     }
                 }
-            }   
-        },
-        // Receive from client.
-        async move {
-            while let Ok((client, message)) = rx.recv().await {
-                if client.includes(user_id) {
-                    if let Ok(message) = serde_json::to_string(&message) {
-                        ws_tx.send(Message::text(message)).await.ok();
-                    }
+            }
+        } => {},
+        // Send to client.
+        _ = async {
+            let mut pings = tokio::time::interval(PING_INTERVAL);
+
+            loop {
+                let message = tokio::select! {
+                    envelope = connection.recv() => match envelope {
+                        Some(envelope) => {
+                            let bytes = protocol::encode(&envelope, encoding);
+                            if encoding.is_binary() {
+                                Message::binary(bytes)
+                            } else {
+                                Message::text(String::from_utf8(bytes).unwrap())
+                            }
+                        }
+                        // The registry dropped this connection because it lagged behind.
+                        None => break,
+                    },
+                    _ = pings.tick() => Message::ping(Vec::new()),
+                };
+
+                if ws_tx.send(message).await.is_err() {
+                    break;
                 }
-            }   
-        }
-    );
-    
+            }
+        } => {},
+    }
+
+    ws_tx.close().await.ok();
+    info!("websocket of user {} closed", user_id);
 }
 
 pub fn serve() -> BoxedFilter<(impl Reply,)> {
@@ -83,12 +160,13 @@ pub fn serve() -> BoxedFilter<(impl Reply,)> {
                 .untuple_one()
                 .and_then(update_session)
                 .or(warp::path("ws")
-                .and(warp::path::end())
-                .and(with_session())
-                .and(warp::ws())
-                .and_then(upgrade_ws)
-                .untuple_one()
-                .and_then(update_session))
+                    .and(warp::path::end())
+                    .and(with_session())
+                    .and(warp::ws())
+                    .and(warp::header::optional::<String>("sec-websocket-protocol"))
+                    .and_then(upgrade_ws)
+                    .untuple_one()
+                    .and_then(update_session)),
         )
         .boxed()
 }
@@ -1,6 +1,7 @@
 mod account;
 mod game;
 mod index;
+mod seasons;
 mod signin;
 mod signup;
 
@@ -12,5 +13,6 @@ pub fn serve() -> BoxedFilter<(impl Reply,)> {
         .or(signin::serve())
         .or(account::serve())
         .or(game::serve())
+        .or(seasons::serve())
         .boxed()
 }
@@ -0,0 +1,79 @@
+use crate::{
+    model::{
+        season::{Season, SeasonId, Standing},
+        session::{update_session, with_session, Layout, Session},
+    },
+    Error,
+};
+use askama::Template;
+use warp::{filters::BoxedFilter, Filter, Rejection, Reply};
+
+#[derive(Template)]
+#[template(path = "seasons.html")]
+struct Seasons {
+    _parent: Layout,
+    seasons: Vec<Season>,
+}
+
+#[derive(Template)]
+#[template(path = "season.html")]
+struct SeasonStandings {
+    _parent: Layout,
+    season: Season,
+    standings: Vec<Standing>,
+}
+
+async fn get_seasons(session: Session) -> Result<(impl Reply, Session), Rejection> {
+    let reply = warp::reply::html(
+        Seasons {
+            _parent: session.get_layout(),
+            seasons: Season::past().await?,
+        }
+        .render()
+        .map_err(|err| Error::from(err))?,
+    );
+
+    Ok((reply, session))
+}
+
+async fn get_season(
+    season_id: SeasonId,
+    session: Session,
+) -> Result<(impl Reply, Session), Rejection> {
+    let season = Season::get(season_id)
+        .await?
+        .ok_or_else(warp::reject::not_found)?;
+
+    let reply = warp::reply::html(
+        SeasonStandings {
+            _parent: session.get_layout(),
+            standings: season.standings().await?,
+            season,
+        }
+        .render()
+        .map_err(|err| Error::from(err))?,
+    );
+
+    Ok((reply, session))
+}
+
+pub fn serve() -> BoxedFilter<(impl Reply,)> {
+    warp::path("game")
+        .and(warp::path("seasons"))
+        .and(
+            warp::path::end()
+                .and(warp::get())
+                .and(with_session())
+                .and_then(get_seasons)
+                .untuple_one()
+                .and_then(update_session)
+                .or(warp::path::param::<SeasonId>()
+                    .and(warp::path::end())
+                    .and(warp::get())
+                    .and(with_session())
+                    .and_then(get_season)
+                    .untuple_one()
+                    .and_then(update_session)),
+        )
+        .boxed()
+}
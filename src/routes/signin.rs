@@ -1,7 +1,8 @@
 use crate::{
     combine,
     model::{
-        session::{update_session, with_session, Flashes, Layout, Session},
+        session::{update_session, with_session, with_session_form, Flashes, Layout, Session},
+        totp,
         user::{extract_password, extract_username, User},
     },
     Error,
@@ -15,6 +16,15 @@ use warp::{filters::BoxedFilter, http::Uri, Filter, Rejection, Reply};
 struct Signin {
     _parent: Layout,
     flashes: Flashes,
+    csrf_token: String,
+}
+
+#[derive(Template)]
+#[template(path = "second_factor.html")]
+struct SecondFactor {
+    _parent: Layout,
+    flashes: Flashes,
+    csrf_token: String,
 }
 
 async fn get_signin(mut session: Session) -> Result<(impl Reply, Session), Rejection> {
@@ -22,6 +32,7 @@ async fn get_signin(mut session: Session) -> Result<(impl Reply, Session), Rejec
         Signin {
             _parent: session.get_layout(),
             flashes: session.get_flashes(),
+            csrf_token: session.get_csrf_token(),
         }
         .render()
         .map_err(|err| Error::from(err))?,
@@ -39,6 +50,14 @@ async fn post_signin(
         password: extract_password(&mut form),
     })) {
         if let Some(user) = session.add_flashes(user.signin().await?) {
+            if let Some(user_id) = totp::required_for(&user.username).await? {
+                session.require_second_factor(user_id).await?;
+                return Ok((
+                    warp::redirect(Uri::from_static("/signin/second-factor")),
+                    session,
+                ));
+            }
+
             session.link_user(user).await?;
             return Ok((warp::redirect(Uri::from_static("/")), session));
         }
@@ -47,21 +66,72 @@ async fn post_signin(
     Ok((warp::redirect(Uri::from_static("/signin")), session))
 }
 
+async fn get_second_factor(mut session: Session) -> Result<(impl Reply, Session), Rejection> {
+    let reply = warp::reply::html(
+        SecondFactor {
+            _parent: session.get_layout(),
+            flashes: session.get_flashes(),
+            csrf_token: session.get_csrf_token(),
+        }
+        .render()
+        .map_err(|err| Error::from(err))?,
+    );
+
+    Ok((reply, session))
+}
+
+async fn post_second_factor(
+    mut session: Session,
+    mut form: HashMap<String, String>,
+) -> Result<(impl Reply, Session), Rejection> {
+    let user_id = match session.get_pending_user_id() {
+        Some(user_id) => user_id,
+        None => return Ok((warp::redirect(Uri::from_static("/signin")), session)),
+    };
+    let code = form.remove("code").unwrap_or_default();
+
+    if session
+        .add_flashes(totp::check(user_id, &code).await?)
+        .is_some()
+    {
+        session.complete_second_factor().await?;
+        return Ok((warp::redirect(Uri::from_static("/")), session));
+    }
+
+    Ok((
+        warp::redirect(Uri::from_static("/signin/second-factor")),
+        session,
+    ))
+}
+
 pub fn serve() -> BoxedFilter<(impl Reply,)> {
     warp::path("signin")
-        .and(warp::path::end())
         .and(
-            warp::get()
-                .and(with_session())
-                .and_then(get_signin)
-                .untuple_one()
-                .and_then(update_session)
-                .or(warp::post()
-                    .and(with_session())
-                    .and(warp::body::form())
-                    .and_then(post_signin)
-                    .untuple_one()
-                    .and_then(update_session)),
+            warp::path::end()
+                .and(
+                    warp::get()
+                        .and(with_session())
+                        .and_then(get_signin)
+                        .untuple_one()
+                        .and_then(update_session)
+                        .or(warp::post()
+                            .and(with_session_form())
+                            .and_then(post_signin)
+                            .untuple_one()
+                            .and_then(update_session)),
+                )
+                .or(warp::path("second-factor").and(warp::path::end()).and(
+                    warp::get()
+                        .and(with_session())
+                        .and_then(get_second_factor)
+                        .untuple_one()
+                        .and_then(update_session)
+                        .or(warp::post()
+                            .and(with_session_form())
+                            .and_then(post_second_factor)
+                            .untuple_one()
+                            .and_then(update_session)),
+                )),
         )
         .boxed()
 }
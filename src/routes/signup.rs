@@ -1,7 +1,7 @@
 use crate::{
     combine,
     model::{
-        session::{update_session, with_session, Flashes, Layout, Session},
+        session::{update_session, with_session, with_session_form, Flashes, Layout, Session},
         user::{extract_confirm_password, extract_username, User},
     },
     Error,
@@ -15,6 +15,7 @@ use warp::{filters::BoxedFilter, http::Uri, Filter, Rejection, Reply};
 struct Signup {
     _parent: Layout,
     flashes: Flashes,
+    csrf_token: String,
 }
 
 async fn get_signup(mut session: Session) -> Result<(impl Reply, Session), Rejection> {
@@ -22,6 +23,7 @@ async fn get_signup(mut session: Session) -> Result<(impl Reply, Session), Rejec
         Signup {
             _parent: session.get_layout(),
             flashes: session.get_flashes(),
+            csrf_token: session.get_csrf_token(),
         }
         .render()
         .map_err(|err| Error::from(err))?,
@@ -57,8 +59,7 @@ pub fn serve() -> BoxedFilter<(impl Reply,)> {
                 .untuple_one()
                 .and_then(update_session)
                 .or(warp::post()
-                    .and(with_session())
-                    .and(warp::body::form())
+                    .and(with_session_form())
                     .and_then(post_signup)
                     .untuple_one()
                     .and_then(update_session)),